@@ -0,0 +1,129 @@
+extern crate rustc_serialize;
+extern crate docopt;
+
+extern crate tftp;
+
+use std::io::{self, Write};
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use docopt::Docopt;
+use tftp::TransferMode;
+use tftp::client::TftpClient;
+
+const USAGE: &'static str = "
+
+Usage:
+  tftp get [options] <host> <remote> [<local>]
+  tftp put [options] <host> <local> [<remote>]
+  tftp (-h | --help)
+  tftp --version
+
+Options:
+  -h --help                         Show this screen
+  --version                         Show version
+  -p --port=<port>                  Port the server is listening on [default: 69]
+  -m --mode=<mode>                  Transfer mode, netascii or octet [default: octet]
+  --blksize=<size>                  Block size (in bytes) to request
+  --windowsize=<blocks>             Window size (in blocks) to request
+  --timeout=<secs>                  Time (in s) allowed before a packet is considered 'lost'
+  --retry=<retry>                   Number of times to retry sending/acknowledging a packet before giving up
+  -q --quiet                        Do not show transfer progress
+";
+
+#[derive(Debug, RustcDecodable)]
+struct Args {
+    cmd_get: bool,
+    cmd_put: bool,
+    arg_host: String,
+    arg_remote: Option<String>,
+    arg_local: Option<String>,
+    flag_port: u16,
+    flag_mode: String,
+    flag_blksize: Option<usize>,
+    flag_windowsize: Option<u16>,
+    flag_timeout: Option<u8>,
+    flag_retry: Option<u8>,
+    flag_quiet: bool
+}
+
+fn main() {
+    let args: Args = Docopt::new(USAGE)
+        .and_then(|d| d.decode())
+        .unwrap_or_else(|e| e.exit());
+
+    let mut client = TftpClient::new((&*args.arg_host, args.flag_port)).unwrap_or_else(|e| {
+        println!("Unable to resolve {}: {}", args.arg_host, e);
+        process::exit(1);
+    });
+
+    match &args.flag_mode.to_lowercase()[..] {
+        "netascii" => client.set_mode(TransferMode::NetAscii),
+        "octet" => client.set_mode(TransferMode::Octet),
+        _ => {
+            println!("Unknown transfer mode: {}", args.flag_mode);
+            process::exit(1);
+        }
+    }
+
+    client.set_block_size(args.flag_blksize);
+    client.set_window_size(args.flag_windowsize);
+    client.set_timeout(args.flag_timeout);
+
+    if args.flag_retry.is_some() {
+        client.set_send_retry_attempts(args.flag_retry.unwrap());
+    }
+
+    if !args.flag_quiet {
+        // Only redraw when at least another 1% (or 64KiB when the size is
+        // unknown) has been transferred
+        let last_step = AtomicUsize::new(0);
+        client.on_progress(move |transferred: &u64, total: &Option<u64>| {
+            let step = match *total {
+                Some(total) if total > 0 => (transferred * 100 / total) as usize,
+                _ => (transferred / 65536) as usize
+            };
+            if step == last_step.swap(step, Ordering::Relaxed) {
+                return;
+            }
+
+            match *total {
+                Some(total) if total > 0 =>
+                    print!("\r{} / {} bytes ({}%)", transferred, total, step),
+                _ => print!("\r{} bytes", transferred)
+            }
+            let _ = io::stdout().flush();
+        });
+    }
+
+    // Either side of a transfer defaults to the file name of the other
+    let result = if args.cmd_get {
+        let remote = args.arg_remote.unwrap();
+        let local = args.arg_local.unwrap_or_else(|| file_name(&remote));
+        client.get(&remote, &local)
+    } else {
+        let local = args.arg_local.unwrap();
+        let remote = args.arg_remote.unwrap_or_else(|| file_name(&local));
+        client.put(&local, &remote)
+    };
+
+    if !args.flag_quiet {
+        println!("");
+    }
+
+    match result {
+        Ok(bytes) => println!("Transferred {} bytes", bytes),
+        Err(e) => {
+            println!("Transfer failed: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+fn file_name(path: &str) -> String {
+    match Path::new(path).file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => path.to_string()
+    }
+}
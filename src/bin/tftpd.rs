@@ -1,19 +1,27 @@
 extern crate rustc_serialize;
 extern crate docopt;
+extern crate libc;
+extern crate log;
 
 extern crate tftp;
 
 use std::time::Duration;
-use std::path::Path;
-use std::fs::File;
+use std::path::{Component, Path, PathBuf};
+use std::net::SocketAddr;
+use std::ops::RangeInclusive;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use docopt::Docopt;
+use tftp::{AbsolutePaths, BusyPolicy, RateLimits, RequestLimits, WritePolicy};
 use tftp::server::TftpServer;
+use tftp::remap::RemapRules;
+use tftp::acl::AccessList;
 
 const USAGE: &'static str = "
 
 Usage:
-  tftpd <root> [<ip> [<port>]] [--retry=<retry>] [--read-timeout=<read_timeout>]
+  tftpd [options] <root> [<ip> [<port>]]
   tftpd (-h | --help)
   tftpd --version
 
@@ -22,6 +30,30 @@ Options:
   --version                         Show version
   --retry=<retry>                   Number of times to retry sending/acknowledging a packet before giving up
   --read-timeout=<read_timeout>     Time (in ms) allowed before a packed is considered 'lost'
+  --max-blksize=<size>              Largest block size (in bytes) a client may negotiate
+  --max-windowsize=<blocks>         Largest window (in blocks) a client may negotiate
+  --rollover=<block>                Block number (0 or 1) that follows block 65535 [default: 0]
+  --max-upload=<bytes>              Largest file (in bytes) a client may write
+  --map-absolute                    Serve absolute paths from under <root> instead of refusing them
+  --map-file=<file>                 Rewrite requested filenames using the rules in <file>
+  --acl-file=<file>                 Allow or deny requests by client and path using the rules in <file>
+  --readonly                        Refuse all write requests (the default)
+  --allow-create                    Allow clients to write new files
+  --allow-overwrite                 Allow clients to write new files and replace existing ones
+  --write-dirs=<dirs>               Only allow writes under these comma-separated directories of <root>
+  --shutdown-timeout=<secs>         Time allowed for transfers to finish after SIGINT or SIGTERM [default: 10]
+  --min-timeout=<secs>              Smallest timeout (in s) a client may negotiate [default: 1]
+  --max-timeout=<secs>              Largest timeout (in s) a client may negotiate [default: 255]
+  --port-range=<low:high>           Only send and receive transfers on local ports in this range
+  --max-transfers=<count>           Largest number of transfers in progress at once
+  --queue=<count>                   Queue up to <count> requests when at --max-transfers instead of refusing them
+  --max-client-transfers=<count>    Largest number of transfers in progress or queued for any one client address
+  --max-requests=<count>            Largest number of requests per second to accept from any one client address
+  --request-burst=<count>           Number of requests a client may make at once under --max-requests [default: 10]
+  --ban-time=<secs>                 Time to ignore a client for after it exceeds --max-requests [default: 60]
+  --max-rate=<bytes>                Largest rate (in bytes/s) to send at, across all transfers
+  --max-client-rate=<bytes>         Largest rate (in bytes/s) to send at to any one client address
+  --max-transfer-rate=<bytes>       Largest rate (in bytes/s) to send at in any one transfer
 ";
 
 #[derive(Debug, RustcDecodable)]
@@ -29,8 +61,102 @@ struct Args {
     arg_root: String,
     arg_ip: Option<String>,
     arg_port: Option<u32>,
-    arg_retry: Option<u8>,
-    arg_read_timeout: Option<u64>
+    flag_retry: Option<u8>,
+    flag_read_timeout: Option<u64>,
+    flag_max_blksize: Option<usize>,
+    flag_max_windowsize: Option<u16>,
+    flag_rollover: u16,
+    flag_max_upload: Option<u64>,
+    flag_map_absolute: bool,
+    flag_map_file: Option<String>,
+    flag_acl_file: Option<String>,
+    flag_readonly: bool,
+    flag_allow_create: bool,
+    flag_allow_overwrite: bool,
+    flag_write_dirs: Option<String>,
+    flag_shutdown_timeout: u64,
+    flag_min_timeout: u64,
+    flag_max_timeout: u64,
+    flag_port_range: Option<String>,
+    flag_max_transfers: Option<usize>,
+    flag_queue: Option<usize>,
+    flag_max_client_transfers: Option<usize>,
+    flag_max_requests: Option<u32>,
+    flag_request_burst: u32,
+    flag_ban_time: u64,
+    flag_max_rate: Option<u64>,
+    flag_max_client_rate: Option<u64>,
+    flag_max_transfer_rate: Option<u64>
+}
+
+// Prints the server's log messages, such as failed transfers, to stderr
+struct StderrLogger;
+
+impl log::Log for StderrLogger {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        eprintln!("{}: {}", record.level(), record.args());
+    }
+
+    fn flush(&self) {
+    }
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+// Set by the signal handler when the server should shut down
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn request_shutdown(_: libc::c_int) {
+    SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+// Shut down cleanly on SIGINT or SIGTERM
+#[cfg(unix)]
+fn handle_shutdown_signals() {
+    unsafe {
+        libc::signal(libc::SIGINT, request_shutdown as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, request_shutdown as libc::sighandler_t);
+    }
+}
+
+#[cfg(not(unix))]
+fn handle_shutdown_signals() {
+}
+
+// Parse a range of ports in the form `<low>:<high>`
+fn parse_port_range(range: &str) -> Option<RangeInclusive<u16>> {
+    let mut parts = range.splitn(2, ':');
+    let low = parts.next().and_then(|p| p.parse::<u16>().ok());
+    let high = parts.next().and_then(|p| p.parse::<u16>().ok());
+    match (low, high) {
+        (Some(low), Some(high)) if low <= high => Some(low..=high),
+        _ => None
+    }
+}
+
+// Parse a directory given to --write-dirs into a path relative to the root,
+// which is how request paths are matched against it. Leading `/` and `./`
+// and trailing `/` are dropped, so "/uploads/" is the same as "uploads".
+fn parse_write_dir(dir: &str) -> Option<PathBuf> {
+    let mut path = PathBuf::new();
+    for component in Path::new(dir).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir | Component::RootDir => (),
+            Component::ParentDir | Component::Prefix(_) => return None
+        }
+    }
+
+    if path.as_os_str().is_empty() {
+        None
+    } else {
+        Some(path)
+    }
 }
 
 fn main() {
@@ -43,28 +169,160 @@ fn main() {
 
     let addr = ip + ":" + &port;
 
-    let mut server = TftpServer::new(&*addr, &args.arg_root).unwrap();
+    if let Err(e) = log::set_logger(&LOGGER) {
+        println!("Could not set up logging: {}", e);
+    }
+    log::set_max_level(log::LevelFilter::Info);
+
+    let mut server = match TftpServer::new(&*addr, &args.arg_root) {
+        Ok(s) => s,
+        Err(e) => {
+            println!("Could not listen on {}: {}", addr, e);
+            process::exit(1);
+        }
+    };
 
-    if args.arg_retry.is_some() {
-        server.set_send_retry_attempts(args.arg_retry.unwrap());
+    if args.flag_retry.is_some() {
+        server.set_send_retry_attempts(args.flag_retry.unwrap());
     }
 
-    if args.arg_read_timeout.is_some() {
+    if args.flag_read_timeout.is_some() {
         server.set_read_timeout(
-            Some(Duration::from_millis(args.arg_read_timeout.unwrap())));
+            Some(Duration::from_millis(args.flag_read_timeout.unwrap())));
     }
 
-    server.on_write_started(|p: &Path, _: &File| {
-        println!("Started write request for: {}", p.to_str().unwrap())
-    }).on_write_completed(|p: &Path, _: &File| {
-        println!("Completed write request for: {}", p.to_str().unwrap())
+    if args.flag_max_blksize.is_some() {
+        server.set_max_block_size(args.flag_max_blksize.unwrap());
+    }
+
+    if args.flag_max_windowsize.is_some() {
+        server.set_max_window_size(args.flag_max_windowsize.unwrap());
+    }
+
+    server.set_block_rollover(args.flag_rollover);
+
+    if args.flag_max_upload.is_some() {
+        server.set_max_upload_size(args.flag_max_upload);
+    }
+
+    if args.flag_map_absolute {
+        server.set_absolute_paths(AbsolutePaths::MapUnderRoot);
+    }
+
+    if let Some(ref path) = args.flag_map_file {
+        match RemapRules::load(path) {
+            Ok(rules) => server.set_remap_rules(rules),
+            Err(e) => {
+                println!("Could not load map file {}: {}", path, e);
+                process::exit(1);
+            }
+        }
+    }
+
+    if let Some(ref path) = args.flag_acl_file {
+        match AccessList::load(path) {
+            Ok(list) => server.set_access_list(list),
+            Err(e) => {
+                println!("Could not load ACL file {}: {}", path, e);
+                process::exit(1);
+            }
+        }
+    }
+
+    // Clients may only write files if they have been allowed to
+    let mut policy = if args.flag_readonly {
+        WritePolicy::ReadOnly
+    } else if args.flag_allow_overwrite {
+        WritePolicy::Overwrite
+    } else if args.flag_allow_create {
+        WritePolicy::CreateOnly
+    } else {
+        WritePolicy::ReadOnly
+    };
+    if let Some(ref dirs) = args.flag_write_dirs {
+        if policy == WritePolicy::ReadOnly {
+            println!("--write-dirs needs --allow-create or --allow-overwrite");
+            process::exit(1);
+        }
+        let mut directories = vec![];
+        for dir in dirs.split(',') {
+            match parse_write_dir(dir) {
+                Some(path) => directories.push((path, policy.clone())),
+                None => {
+                    println!("Invalid directory {:?} in --write-dirs, expected one under <root>", dir);
+                    process::exit(1);
+                }
+            }
+        }
+        policy = WritePolicy::PerDirectory(directories);
+    }
+    server.set_write_policy(policy);
+
+    server.set_timeout_bounds(Duration::from_secs(args.flag_min_timeout),
+                              Duration::from_secs(args.flag_max_timeout));
+
+    if let Some(ref range) = args.flag_port_range {
+        match parse_port_range(range) {
+            Some(ports) => server.set_port_range(Some(ports)),
+            None => {
+                println!("Invalid port range {}, expected <low>:<high>", range);
+                process::exit(1);
+            }
+        }
+    }
+
+    server.set_max_concurrent_transfers(args.flag_max_transfers);
+    if let Some(queued) = args.flag_queue {
+        server.set_busy_policy(BusyPolicy::Queue(queued));
+    }
+    server.set_max_transfers_per_client(args.flag_max_client_transfers);
+
+    if let Some(per_second) = args.flag_max_requests {
+        server.set_request_limits(RequestLimits{
+            per_second: per_second,
+            burst: args.flag_request_burst,
+            ban: Duration::from_secs(args.flag_ban_time)
+        });
+    }
+
+    let limits = RateLimits{
+        global: args.flag_max_rate,
+        per_client: args.flag_max_client_rate,
+        per_transfer: args.flag_max_transfer_rate
+    };
+    if limits != RateLimits::default() {
+        server.set_rate_limits(limits);
+    }
+
+    server.on_write_started(|p: &Path, addr: &SocketAddr| {
+        println!("Started write request for: {} from {}", p.display(), addr)
+    }).on_write_completed(|p: &Path, addr: &SocketAddr| {
+        println!("Completed write request for: {} from {}", p.display(), addr)
     });
 
-    server.on_read_started(|p: &Path, _: &File| {
-        println!("Started read request for: {}", p.to_str().unwrap())
-    }).on_read_completed(|p: &Path, _: &File| {
-        println!("Completed read request for: {}", p.to_str().unwrap())
+    server.on_read_started(|p: &Path, addr: &SocketAddr| {
+        println!("Started read request for: {} from {}", p.display(), addr)
+    }).on_read_completed(|p: &Path, addr: &SocketAddr| {
+        println!("Completed read request for: {} from {}", p.display(), addr)
     });
 
-    server.start();
+    handle_shutdown_signals();
+    if let Err(e) = server.serve_until(|| SHUTDOWN_REQUESTED.load(Ordering::SeqCst)) {
+        println!("Could not receive requests: {}", e);
+        process::exit(1);
+    }
+
+    let stats = server.handle().stats();
+    println!("Received {} requests: {} retransmitted, {} denied, {} refused while busy, \
+              {} refused over the per-client limit, {} dropped for flooding ({} bans)",
+             stats.requests, stats.duplicate_requests, stats.denied_requests,
+             stats.busy_rejections, stats.client_limit_rejections, stats.flood_drops,
+             stats.bans);
+
+    println!("Shutting down, waiting for transfers in progress to finish");
+    let timeout = Duration::from_secs(args.flag_shutdown_timeout);
+    if !server.handle().shutdown(Some(timeout)) {
+        println!("Gave up waiting for transfers after {} seconds", args.flag_shutdown_timeout);
+        process::exit(1);
+    }
 }
@@ -0,0 +1,219 @@
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::net::IpAddr;
+use std::path::Path;
+
+use regex::Regex;
+
+use crate::cidr::Cidr;
+use crate::remap::ParseError;
+
+/// What happens to a request matched by a rule
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    /// Serve the request
+    Allow,
+
+    /// Refuse the request with an access violation
+    Deny,
+
+    /// Ignore the request without responding, as if the server wasn't there
+    Drop
+}
+
+/// The kind of request a rule applies to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Read,
+    Write,
+
+    /// Both read and write requests
+    Any
+}
+
+/// A single access rule, which applies to requests from clients in `client`
+/// (or from any client, if it is None) for paths matching `path` (or for any
+/// path, if it is None).
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub permission: Permission,
+    pub operation: Operation,
+    pub client: Option<Cidr>,
+    pub path: Option<Regex>
+}
+
+/// An ordered list of rules deciding which clients may read and write which
+/// files. The first rule that matches a request decides it, and requests
+/// that match no rule are allowed.
+#[derive(Debug, Clone, Default)]
+pub struct AccessList {
+    rules: Vec<Rule>
+}
+
+impl AccessList {
+
+    /// Create an empty list of rules, which allows every request.
+    pub fn new() -> AccessList {
+        AccessList::default()
+    }
+
+    /// Add a rule to the end of the list.
+    pub fn push(&mut self, rule: Rule) -> &mut Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Parse a list of rules, one per line, in the form
+    ///
+    /// ```text
+    /// <permission> <operation> <address block> [<path regex>]
+    /// ```
+    ///
+    /// where the permission is `allow`, `deny` or `drop`, the operation is
+    /// `read`, `write` or `any`, and the address block may also be `any`.
+    /// Blank lines and lines starting with `#` are ignored. For example:
+    ///
+    /// ```text
+    /// # Only the build network may upload, and only to incoming/
+    /// allow write 10.1.0.0/16 ^incoming/
+    /// deny  write any
+    /// allow read  10.0.0.0/8
+    /// allow read  fd00::/8
+    /// drop  any   any
+    /// ```
+    pub fn parse(text: &str) -> Result<AccessList, ParseError> {
+        let mut list = AccessList::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            match parse_rule(line) {
+                Ok(rule) => list.push(rule),
+                Err(message) => return Err(ParseError{
+                    line: index + 1,
+                    message: message
+                })
+            };
+        }
+        Ok(list)
+    }
+
+    /// Read and parse a file of rules, as described for `parse`. A file
+    /// that can't be parsed is reported as an `InvalidData` error.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<AccessList> {
+        let mut text = String::new();
+        let mut file = File::open(path)?;
+        file.read_to_string(&mut text)?;
+        AccessList::parse(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Decide a request from `client` for `path`, relative to the root. If
+    /// the path isn't known, for instance because the request is malformed,
+    /// rules with a path pattern don't match it.
+    pub fn check(&self, operation: Operation, client: &IpAddr, path: Option<&Path>) -> Permission {
+        for rule in &self.rules {
+            if rule.operation != Operation::Any && rule.operation != operation {
+                continue;
+            }
+
+            let client_matches = match rule.client {
+                Some(ref block) => block.contains(client),
+                None => true
+            };
+            let path_matches = match (&rule.path, path) {
+                (&Some(ref pattern), Some(path)) => pattern.is_match(&path.to_string_lossy()),
+                (&Some(_), None) => false,
+                (&None, _) => true
+            };
+
+            if client_matches && path_matches {
+                return rule.permission;
+            }
+        }
+        Permission::Allow
+    }
+}
+
+// Parse a single non-empty, non-comment line of a rule file
+fn parse_rule(line: &str) -> Result<Rule, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    if words.len() < 3 || words.len() > 4 {
+        return Err("expected a permission, an operation, an address block and an \
+                    optional path pattern".to_string());
+    }
+
+    let permission = match words[0] {
+        "allow" => Permission::Allow,
+        "deny" => Permission::Deny,
+        "drop" => Permission::Drop,
+        other => return Err(format!("unknown permission '{}'", other))
+    };
+
+    let operation = match words[1] {
+        "read" => Operation::Read,
+        "write" => Operation::Write,
+        "any" => Operation::Any,
+        other => return Err(format!("unknown operation '{}'", other))
+    };
+
+    let client = match words[2] {
+        "any" => None,
+        block => match block.parse::<Cidr>() {
+            Ok(c) => Some(c),
+            Err(e) => return Err(e.to_string())
+        }
+    };
+
+    let path = match words.get(3) {
+        Some(pattern) => match Regex::new(pattern) {
+            Ok(p) => Some(p),
+            Err(e) => return Err(format!("invalid pattern: {}", e))
+        },
+        None => None
+    };
+
+    Ok(Rule{
+        permission: permission,
+        operation: operation,
+        client: client,
+        path: path
+    })
+}
+
+#[test]
+fn access_list_checks_rules_in_order() {
+    let list = AccessList::parse(r#"
+        # Comments and blank lines are skipped
+
+        allow write 10.1.0.0/16 ^incoming/
+        deny  write any
+        allow read  10.0.0.0/8
+        allow read  fd00::/8
+        drop  any   any
+    "#).unwrap();
+
+    let builder: IpAddr = "10.1.2.3".parse().unwrap();
+    let booting: IpAddr = "10.9.0.1".parse().unwrap();
+    let booting_v6: IpAddr = "fd00::1".parse().unwrap();
+    let outside: IpAddr = "192.0.2.1".parse().unwrap();
+    let incoming = Some(Path::new("incoming/image.bin"));
+    let kernel = Some(Path::new("vmlinuz"));
+
+    assert_eq!(list.check(Operation::Write, &builder, incoming), Permission::Allow);
+    assert_eq!(list.check(Operation::Write, &builder, kernel), Permission::Deny);
+    assert_eq!(list.check(Operation::Write, &builder, None), Permission::Deny);
+    assert_eq!(list.check(Operation::Write, &booting, incoming), Permission::Deny);
+    assert_eq!(list.check(Operation::Read, &booting, kernel), Permission::Allow);
+    assert_eq!(list.check(Operation::Read, &booting_v6, kernel), Permission::Allow);
+    assert_eq!(list.check(Operation::Read, &outside, kernel), Permission::Drop);
+    assert_eq!(AccessList::new().check(Operation::Write, &outside, None), Permission::Allow);
+
+    assert_eq!(AccessList::parse("allow read").unwrap_err().line, 1);
+    assert_eq!(AccessList::parse("\nallow read 10.0.0.0/40").unwrap_err().line, 2);
+    assert!(AccessList::parse("permit read any").is_err());
+    assert!(AccessList::parse("allow list any").is_err());
+    assert!(AccessList::parse("deny read any (").is_err());
+}
@@ -1,3 +1,9 @@
+use std::net::SocketAddr;
+use std::path::Path;
+
+use crate::codes::TransferMode;
+use crate::server::ReadResponse;
+
 /// A simple trait representing a callable that will be invoked after some
 /// event has occurred.
 pub trait Callback<T: ?Sized, U: ?Sized>: Sync + Send {
@@ -10,3 +16,19 @@ impl<F, T: ?Sized, U: ?Sized> Callback<T, U> for F where F: Fn(&T, &U), F: Sync
         self(arg1, arg2)
     }
 }
+
+/// A handler that decides how a request to read a file is answered. It is
+/// passed the requested path, relative to the root, the address of the
+/// client, and the transfer mode. Returning None serves the file from
+/// storage as usual.
+pub trait ReadHandler: Sync + Send {
+    fn handle(&self, path: &Path, addr: &SocketAddr, mode: TransferMode) -> Option<ReadResponse>;
+}
+
+/// A default implementation for Fn
+impl<F> ReadHandler for F
+    where F: Fn(&Path, &SocketAddr, TransferMode) -> Option<ReadResponse>, F: Sync + Send {
+    fn handle(&self, path: &Path, addr: &SocketAddr, mode: TransferMode) -> Option<ReadResponse> {
+        self(path, addr, mode)
+    }
+}
@@ -0,0 +1,121 @@
+use std::error;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// A block of IPv4 or IPv6 addresses, such as `10.0.0.0/8` or `fd00::/8`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8
+}
+
+impl Cidr {
+
+    /// Create the block of addresses that share the first `prefix` bits of
+    /// `addr`. Returns None if `prefix` is longer than the address.
+    pub fn new(addr: IpAddr, prefix: u8) -> Option<Cidr> {
+        let max = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128
+        };
+        if prefix > max {
+            return None;
+        }
+        Some(Cidr{
+            network: addr,
+            prefix: prefix
+        })
+    }
+
+    /// Whether `addr` is in this block. IPv4 addresses mapped into IPv6
+    /// (`::ffff:a.b.c.d`) are treated as the IPv4 address they contain.
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        let addr = match *addr {
+            IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+                Some(v4) => IpAddr::V4(v4),
+                None => IpAddr::V6(v6)
+            },
+            v4 => v4
+        };
+
+        match (self.network, addr) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                let mask = (!0u64 << (32 - self.prefix as u32)) as u32;
+                u32::from(network) & mask == u32::from(addr) & mask
+            },
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                let mask = match self.prefix {
+                    0 => 0,
+                    prefix => !0u128 << (128 - prefix as u32)
+                };
+                u128::from(network) & mask == u128::from(addr) & mask
+            },
+            _ => false
+        }
+    }
+}
+
+/// The error returned when a string is not a valid CIDR block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseCidrError(String);
+
+impl fmt::Display for ParseCidrError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid address block '{}'", self.0)
+    }
+}
+
+impl error::Error for ParseCidrError {
+    fn description(&self) -> &str {
+        "invalid address block"
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = ParseCidrError;
+
+    // Parse `<address>/<prefix>`, or a lone address, which is a block of one
+    fn from_str(s: &str) -> Result<Cidr, ParseCidrError> {
+        let invalid = || ParseCidrError(s.to_string());
+        let mut parts = s.splitn(2, '/');
+
+        let addr = match parts.next().and_then(|a| a.parse::<IpAddr>().ok()) {
+            Some(a) => a,
+            None => return Err(invalid())
+        };
+        let prefix = match parts.next() {
+            Some(p) => match p.parse::<u8>() {
+                Ok(p) => p,
+                Err(_) => return Err(invalid())
+            },
+            None => match addr {
+                IpAddr::V4(_) => 32,
+                IpAddr::V6(_) => 128
+            }
+        };
+        Cidr::new(addr, prefix).ok_or_else(invalid)
+    }
+}
+
+#[test]
+fn cidr_contains() {
+    let block: Cidr = "10.1.0.0/16".parse().unwrap();
+    assert!(block.contains(&"10.1.200.3".parse().unwrap()));
+    assert!(block.contains(&"::ffff:10.1.0.1".parse().unwrap()));
+    assert!(!block.contains(&"10.2.0.1".parse().unwrap()));
+    assert!(!block.contains(&"fd00::1".parse().unwrap()));
+
+    let block: Cidr = "fd00::/8".parse().unwrap();
+    assert!(block.contains(&"fd12:3456::1".parse().unwrap()));
+    assert!(!block.contains(&"fe80::1".parse().unwrap()));
+
+    let any: Cidr = "0.0.0.0/0".parse().unwrap();
+    assert!(any.contains(&"192.0.2.1".parse().unwrap()));
+    let single: Cidr = "192.0.2.1".parse().unwrap();
+    assert!(single.contains(&"192.0.2.1".parse().unwrap()));
+    assert!(!single.contains(&"192.0.2.2".parse().unwrap()));
+
+    assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+    assert!("10.0.0/8".parse::<Cidr>().is_err());
+}
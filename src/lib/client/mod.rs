@@ -0,0 +1,538 @@
+use std::net::{UdpSocket, SocketAddr, ToSocketAddrs};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::time::Duration;
+use std::sync::Arc;
+
+use crate::codes::{ErrorCode, TransferMode, Opcode};
+use crate::options::TransferOptions;
+use crate::packet::Packet;
+use crate::packet::error::TftpError;
+use crate::packet::data::{self, TftpData};
+use crate::packet::ack::TftpAck;
+use crate::packet::oack::TftpOack;
+use crate::packet::request::TftpRequest;
+use crate::transfer::{TransferError, receive_blocks, send_blocks};
+use crate::callback::Callback;
+use crate::error::Error;
+use crate::netascii::{self, NetAsciiEncoder, NetAsciiDecoder};
+use crate::socket::{TransferSocket, block_on};
+#[cfg(feature = "async")]
+use crate::socket::AsyncSocket;
+
+pub struct TftpClient {
+    server: SocketAddr,
+    mode: TransferMode,
+
+    block_size: Option<usize>,
+    window_size: Option<u16>,
+    timeout: Option<u8>,
+    rollover: Option<u16>,
+    send_retry_attempts: u8,
+
+    progress_callback: Option<Arc<Callback<u64, Option<u64>>>>
+}
+
+impl TftpClient {
+
+    /// Create a client that transfers files to and from the TFTP server at
+    /// the given address, in octet mode.
+    ///
+    /// By default, no options are requested other than `tsize`, the client
+    /// waits 1 second for each response, and attempts to re-send an
+    /// unacknowledged packet up to 5 times.
+    ///
+    /// # Failures
+    /// Returns `Err` if the address cannot be resolved
+    pub fn new<A: ToSocketAddrs>(addr: A) -> Result<TftpClient, Error> {
+        let server = match addr.to_socket_addrs()?.next() {
+            Some(a) => a,
+            None => return Err(Error::Io(io::Error::new(io::ErrorKind::InvalidInput,
+                                                        "No address to connect to")))
+        };
+
+        Ok(TftpClient {
+            server: server,
+            mode: TransferMode::Octet,
+
+            block_size: None,
+            window_size: None,
+            timeout: None,
+            rollover: None,
+            send_retry_attempts: 5,
+
+            progress_callback: None
+        })
+    }
+
+    /// Download the file `remote` from the server and store it at `local`.
+    /// Returns the number of bytes received. If the transfer fails, `local`
+    /// is removed.
+    pub fn get<P: AsRef<Path>>(&self, remote: &str, local: P) -> Result<u64, Error> {
+        let mut file = File::create(&local)?;
+        let result = self.get_into(remote, &mut file);
+        if result.is_err() {
+            let _ = fs::remove_file(&local);
+        }
+        result
+    }
+
+    /// Upload the file at `local` to the server as `remote`. Returns the
+    /// number of bytes sent.
+    pub fn put<P: AsRef<Path>>(&self, local: P, remote: &str) -> Result<u64, Error> {
+        // The size sent to the server must be the size after translation
+        let size = match self.mode {
+            TransferMode::NetAscii => netascii::encoded_size(&mut File::open(&local)?)?,
+            TransferMode::Octet => fs::metadata(&local)?.len()
+        };
+
+        let mut file = File::open(&local)?;
+        self.put_from(&mut file, Some(size), remote)
+    }
+
+    /// Download the file `remote` from the server into `writer`. Returns the
+    /// number of bytes received, before any netascii translation.
+    pub fn get_into<W: Write>(&self, remote: &str, writer: &mut W) -> Result<u64, Error> {
+        block_on(self.receive(self.bind()?, remote, writer))
+    }
+
+    /// Upload the contents of `reader` to the server as `remote`. If `size`
+    /// is known, it is sent to the server with the `tsize` option. In
+    /// netascii mode, `size` must be the size after translation. Returns the
+    /// number of bytes sent, after any netascii translation.
+    pub fn put_from<R: Read>(&self, reader: &mut R, size: Option<u64>,
+                             remote: &str) -> Result<u64, Error> {
+        block_on(self.send(self.bind()?, reader, size, remote))
+    }
+
+    /// Download the file `remote` from the server into `writer`, as with
+    /// `get_into`, on the current tokio runtime.
+    #[cfg(feature = "async")]
+    pub async fn get_into_async<W: Write>(&self, remote: &str,
+                                          writer: &mut W) -> Result<u64, Error> {
+        let socket = AsyncSocket::bind(self.local_addr()).await?;
+        self.receive(self.with_timeout(socket)?, remote, writer).await
+    }
+
+    /// Upload the contents of `reader` to the server as `remote`, as with
+    /// `put_from`, on the current tokio runtime.
+    #[cfg(feature = "async")]
+    pub async fn put_from_async<R: Read>(&self, reader: &mut R, size: Option<u64>,
+                                         remote: &str) -> Result<u64, Error> {
+        let socket = AsyncSocket::bind(self.local_addr()).await?;
+        self.send(self.with_timeout(socket)?, reader, size, remote).await
+    }
+
+    // Download the file `remote` over `socket`, translating it into `writer`
+    async fn receive<S: TransferSocket, W: Write>(&self, socket: S, remote: &str,
+                                                  writer: &mut W) -> Result<u64, Error> {
+        match self.mode {
+            TransferMode::NetAscii => {
+                let mut decoder = NetAsciiDecoder::new(writer);
+                let received = self.download(socket, remote, &mut decoder).await?;
+                decoder.finish()?;
+                Ok(received)
+            },
+            TransferMode::Octet => self.download(socket, remote, writer).await
+        }
+    }
+
+    // Upload the contents of `reader` over `socket`, translating it first
+    async fn send<S: TransferSocket, R: Read>(&self, socket: S, reader: &mut R,
+                                              size: Option<u64>, remote: &str) -> Result<u64, Error> {
+        match self.mode {
+            TransferMode::NetAscii => {
+                self.upload(socket, &mut NetAsciiEncoder::new(reader), size, remote).await
+            },
+            TransferMode::Octet => self.upload(socket, reader, size, remote).await
+        }
+    }
+
+    // Run a read request, writing the data received to `writer`
+    async fn download<S: TransferSocket, W: Write>(&self, mut socket: S, remote: &str,
+                                                   writer: &mut W) -> Result<u64, Error> {
+        let requested = self.requested_options(Some(0));
+        let (response, peer) = self.send_request(&socket, Opcode::ReadRequest,
+                                                 remote, &requested).await?;
+
+        let result = if let Some(oack) = TftpOack::from_buffer(&response) {
+            let options = self.accept_oack(&mut socket, peer, &requested, &oack).await?;
+            let mut writer = self.progress(writer, options.transfer_size);
+            receive_blocks(&socket, peer, &options, self.send_retry_attempts, &mut writer,
+                           0, TftpAck{number: 0}.as_packet(), None).await
+        } else if let Some(data) = TftpData::from_buffer(&response) {
+            // The server ignored the options, so the first block has
+            // already arrived
+            if data.number != 1 {
+                return Err(Error::Protocol("Transfer did not start at block 1".to_string()));
+            }
+            let options = TransferOptions::default();
+            let mut writer = self.progress(writer, None);
+            if let Err(e) = writer.write_all(&data.data) {
+                let _ = socket.send_to(&TftpError{
+                    code: ErrorCode::Undefined,
+                    message: None
+                }.as_packet(), peer).await;
+                return Err(Error::Io(e));
+            }
+
+            let first = data.data.len() as u64;
+            if data.data.len() < options.block_size {
+                socket.send_to(&TftpAck{number: 1}.as_packet(), peer).await?;
+                Ok(first)
+            } else {
+                receive_blocks(&socket, peer, &options, self.send_retry_attempts, &mut writer,
+                               1, TftpAck{number: 1}.as_packet(), None).await.map(|n| first + n)
+            }
+        } else {
+            return Err(self.unexpected_response(&response));
+        };
+        self.finish(&socket, peer, result).await
+    }
+
+    // Run a write request, sending the data read from `reader`
+    async fn upload<S: TransferSocket, R: Read>(&self, mut socket: S, reader: &mut R,
+                                                size: Option<u64>, remote: &str) -> Result<u64, Error> {
+        let requested = self.requested_options(size);
+        let (response, peer) = self.send_request(&socket, Opcode::WriteRequest,
+                                                 remote, &requested).await?;
+
+        let options = if let Some(oack) = TftpOack::from_buffer(&response) {
+            self.accept_oack(&mut socket, peer, &requested, &oack).await?
+        } else if let Some(ack) = TftpAck::from_buffer(&response) {
+            if ack.number != 0 {
+                return Err(Error::Protocol("Write request acknowledged with a non-zero block".to_string()));
+            }
+            TransferOptions::default()
+        } else {
+            return Err(self.unexpected_response(&response));
+        };
+
+        let mut reader = self.progress(reader, size);
+        let result = send_blocks(&socket, peer, &options, self.send_retry_attempts,
+                                 &mut reader, None).await;
+        self.finish(&socket, peer, result).await
+    }
+
+    /// Set a callback function to be invoked as a transfer progresses. This
+    /// callback will be passed the number of bytes transferred so far, and
+    /// the size of the file if it is known.
+    pub fn on_progress<F: Callback<u64, Option<u64>> + 'static>(&mut self, callback: F) -> &mut Self {
+        self.progress_callback = Some(Arc::new(callback));
+        self
+    }
+
+    /// Set the transfer mode used for subsequent requests
+    pub fn set_mode(&mut self, mode: TransferMode) {
+        self.mode = mode;
+    }
+
+    /// Set the block size to request with the `blksize` option. If the value
+    /// specified is None, the option is not sent and 512 byte blocks are
+    /// used.
+    pub fn set_block_size(&mut self, size: Option<usize>) {
+        self.block_size = size;
+    }
+
+    /// Set the number of blocks the server may send before waiting for an
+    /// ACK, requested with the `windowsize` option. If the value specified is
+    /// None, the option is not sent.
+    pub fn set_window_size(&mut self, size: Option<u16>) {
+        self.window_size = size;
+    }
+
+    /// Set the time (in seconds) to wait for a response before re-sending a
+    /// packet. The same timeout is requested from the server with the
+    /// `timeout` option. If the value specified is None, the option is not
+    /// sent and the client waits for 1 second.
+    pub fn set_timeout(&mut self, secs: Option<u8>) {
+        self.timeout = secs;
+    }
+
+    /// Set the block number (0 or 1) that follows block 65535, requested
+    /// with the `rollover` option. If the value specified is None, or the
+    /// server does not acknowledge the option, block numbers wrap around to
+    /// 0.
+    pub fn set_block_rollover(&mut self, first: Option<u16>) {
+        self.rollover = first;
+    }
+
+    /// Set the number of times the client will attempt to re-transmit a
+    /// packet that did not receive a response.
+    pub fn set_send_retry_attempts(&mut self, attempts: u8) {
+        self.send_retry_attempts = attempts;
+    }
+
+    // Bind a socket for a single transfer. Its port is the client's transfer
+    // ID, so every transfer gets a new one.
+    fn bind(&self) -> Result<UdpSocket, Error> {
+        self.with_timeout(UdpSocket::bind(self.local_addr())?)
+    }
+
+    // The address to bind transfer sockets to, which must be of the same
+    // family as the server's
+    fn local_addr(&self) -> SocketAddr {
+        if self.server.is_ipv4() {
+            SocketAddr::from(([0, 0, 0, 0], 0))
+        } else {
+            SocketAddr::from(([0u16; 8], 0))
+        }
+    }
+
+    // Wait for responses on `socket` for the configured timeout
+    fn with_timeout<S: TransferSocket>(&self, mut socket: S) -> Result<S, Error> {
+        let timeout = Duration::from_secs(self.timeout.unwrap_or(1) as u64);
+        socket.set_timeout(Some(timeout))?;
+        Ok(socket)
+    }
+
+    // The options to request from the server. `size` is the value of the
+    // `tsize` option.
+    fn requested_options(&self, size: Option<u64>) -> Vec<(String, String)> {
+        let mut options = vec![];
+        if let Some(size) = self.block_size {
+            options.push(("blksize".to_string(), size.to_string()));
+        }
+        if let Some(size) = self.window_size {
+            options.push(("windowsize".to_string(), size.to_string()));
+        }
+        if let Some(secs) = self.timeout {
+            options.push(("timeout".to_string(), secs.to_string()));
+        }
+        if let Some(first) = self.rollover {
+            options.push(("rollover".to_string(), first.to_string()));
+        }
+        if let Some(size) = size {
+            options.push(("tsize".to_string(), size.to_string()));
+        }
+        options
+    }
+
+    // Send a read or write request to the server until it responds. The
+    // server replies from a new port, which is its transfer ID, so the
+    // response is returned along with the address it came from.
+    async fn send_request<S: TransferSocket>(&self, socket: &S, opcode: Opcode, filename: &str,
+                                             options: &[(String, String)])
+                                             -> Result<(Vec<u8>, SocketAddr), Error> {
+        let request = TftpRequest{
+            opcode: opcode,
+            filename: filename.to_string(),
+            mode: self.mode,
+            options: options.to_vec()
+        }.as_packet();
+
+        // Large enough for a DATA packet of any block size
+        let mut buffer = vec![0u8; data::MAX_BLOCK_SIZE + 2 + 2];
+
+        let mut attempts = 0;
+        while attempts <= self.send_retry_attempts {
+            attempts += 1;
+            socket.send_to(&request, self.server).await?;
+
+            loop {
+                let (count, addr) = match socket.recv_from(&mut buffer).await {
+                    Ok(r) => r,
+                    Err(_) => break
+                };
+
+                // The response may come from any port, but only from the
+                // host the request was sent to
+                if addr.ip() != self.server.ip() {
+                    continue;
+                }
+
+                if let Some(error) = TftpError::from_buffer(&buffer[..count]) {
+                    return Err(Error::Remote(error));
+                }
+                buffer.truncate(count);
+                return Ok((buffer, addr));
+            }
+        }
+        Err(Error::TimedOut)
+    }
+
+    // Check that the options acknowledged by the server are acceptable. If
+    // they are not, the server is told the transfer is being abandoned.
+    async fn accept_oack<S: TransferSocket>(&self, socket: &mut S, peer: SocketAddr,
+                                            requested: &[(String, String)],
+                                            oack: &TftpOack) -> Result<TransferOptions, Error> {
+        let options = match TransferOptions::from_oack(requested, &oack.options) {
+            Some(o) => o,
+            None => {
+                let _ = socket.send_to(&TftpError{
+                    code: ErrorCode::OptionNegotiation,
+                    message: None
+                }.as_packet(), peer).await;
+                return Err(Error::Protocol("Server acknowledged invalid options".to_string()));
+            }
+        };
+
+        if let Some(timeout) = options.timeout {
+            socket.set_timeout(Some(timeout))?;
+        }
+        Ok(options)
+    }
+
+    // Report a failed transfer to the server, if it does not already know
+    async fn finish<S: TransferSocket>(&self, socket: &S, peer: SocketAddr,
+                                       result: Result<u64, TransferError>) -> Result<u64, Error> {
+        match result {
+            Ok(n) => Ok(n),
+            Err(e) => {
+                if let Some(error) = e.as_tftp_error() {
+                    let _ = socket.send_to(&error.as_packet(), peer).await;
+                }
+                Err(Error::from(e))
+            }
+        }
+    }
+
+    // Wrap a stream so that reading or writing it reports the progress of
+    // the transfer
+    fn progress<S>(&self, stream: S, total: Option<u64>) -> Progress<S> {
+        Progress{
+            stream: stream,
+            transferred: 0,
+            total: total,
+            callback: self.progress_callback.clone()
+        }
+    }
+
+    fn unexpected_response(&self, response: &[u8]) -> Error {
+        let opcode = if response.len() >= 2 { response[1] } else { 0 };
+        Error::Protocol(format!("Unexpected response with opcode {}", opcode))
+    }
+}
+
+// A stream that invokes a progress callback as data passes through it
+struct Progress<S> {
+    stream: S,
+    transferred: u64,
+    total: Option<u64>,
+    callback: Option<Arc<Callback<u64, Option<u64>>>>
+}
+
+impl<S> Progress<S> {
+    fn advance(&mut self, count: usize) {
+        self.transferred += count as u64;
+        if let Some(ref callback) = self.callback {
+            callback.call(&self.transferred, &self.total);
+        }
+    }
+}
+
+impl<R: Read> Read for Progress<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.stream.read(buf)?;
+        self.advance(count);
+        Ok(count)
+    }
+}
+
+impl<W: Write> Write for Progress<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = self.stream.write(buf)?;
+        self.advance(count);
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+// Play the part of a server on loopback. Once a request arrives, `script` is
+// run with a socket on a new port, as the server's transfer ID, and the
+// address the request came from. Returns the address to send requests to.
+#[cfg(test)]
+fn fake_server<F>(script: F) -> (SocketAddr, std::thread::JoinHandle<()>)
+    where F: FnOnce(UdpSocket, SocketAddr) + Send + 'static {
+    use std::thread;
+
+    let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let mut buffer = [0u8; 512];
+        let (_, client) = listener.recv_from(&mut buffer).unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        script(socket, client);
+    });
+    (addr, server)
+}
+
+// Receive a packet on `socket`, and check that it came from `from`
+#[cfg(test)]
+fn receive_from(socket: &UdpSocket, from: SocketAddr) -> Vec<u8> {
+    let mut buffer = [0u8; 1024];
+    let (count, addr) = socket.recv_from(&mut buffer).unwrap();
+    assert_eq!(addr, from);
+    buffer[..count].to_vec()
+}
+
+#[test]
+fn client_locks_to_server_transfer_id() {
+    let (addr, server) = fake_server(|socket, client| {
+        socket.send_to(&TftpData{number: 1, data: vec![1; 512]}.as_packet(), client).unwrap();
+        assert_eq!(receive_from(&socket, client), TftpAck{number: 1}.as_packet());
+
+        // A packet from any other port is refused, without ending the
+        // transfer
+        let stray = UdpSocket::bind("127.0.0.1:0").unwrap();
+        stray.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stray.send_to(&TftpData{number: 2, data: vec![3; 10]}.as_packet(), client).unwrap();
+        let error = TftpError::from_buffer(&receive_from(&stray, client)).unwrap();
+        assert_eq!(error.code, ErrorCode::UnknownTransferID);
+
+        socket.send_to(&TftpData{number: 2, data: vec![2; 10]}.as_packet(), client).unwrap();
+        assert_eq!(receive_from(&socket, client), TftpAck{number: 2}.as_packet());
+    });
+
+    let mut received = vec![];
+    assert_eq!(TftpClient::new(addr).unwrap().get_into("a.bin", &mut received).unwrap(), 522);
+    assert_eq!(received[..512], [1; 512][..]);
+    assert_eq!(received[512..], [2; 10][..]);
+    server.join().unwrap();
+}
+
+#[test]
+fn client_accepts_data_when_options_ignored() {
+    // A server that doesn't support options sends the first block straight
+    // away, using the default block size
+    let (addr, server) = fake_server(|socket, client| {
+        socket.send_to(&TftpData{number: 1, data: vec![1; 512]}.as_packet(), client).unwrap();
+        assert_eq!(receive_from(&socket, client), TftpAck{number: 1}.as_packet());
+        socket.send_to(&TftpData{number: 2, data: vec![]}.as_packet(), client).unwrap();
+        assert_eq!(receive_from(&socket, client), TftpAck{number: 2}.as_packet());
+    });
+
+    let mut client = TftpClient::new(addr).unwrap();
+    client.set_block_size(Some(1024));
+    client.set_window_size(Some(4));
+    let mut received = vec![];
+    assert_eq!(client.get_into("a.bin", &mut received).unwrap(), 512);
+    assert_eq!(received, vec![1; 512]);
+    server.join().unwrap();
+}
+
+#[test]
+fn client_rejects_invalid_oack() {
+    // A larger block size than the client asked for
+    let (addr, server) = fake_server(|socket, client| {
+        socket.send_to(&TftpOack{
+            options: vec![("blksize".to_string(), "2048".to_string())]
+        }.as_packet(), client).unwrap();
+        let error = TftpError::from_buffer(&receive_from(&socket, client)).unwrap();
+        assert_eq!(error.code, ErrorCode::OptionNegotiation);
+    });
+
+    let mut client = TftpClient::new(addr).unwrap();
+    client.set_block_size(Some(1024));
+    let mut received = vec![];
+    match client.get_into("a.bin", &mut received) {
+        Err(Error::Protocol(_)) => (),
+        r => panic!("invalid OACK was accepted: {:?}", r)
+    }
+    server.join().unwrap();
+}
@@ -1,20 +1,21 @@
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TransferMode {
     NetAscii,
     Octet,
     // 'email' is unsupported
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub enum Opcode {
     ReadRequest,
     WriteRequest,
     Data,
     Acknowledgment,
     Error,
+    OptionAcknowledgment,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ErrorCode {
     Undefined = 0,
     FileNotFound,
@@ -23,5 +24,6 @@ pub enum ErrorCode {
     IllegalOperation,
     UnknownTransferID,
     FileExists,
-    NoSuchUser
+    NoSuchUser,
+    OptionNegotiation
 }
@@ -1,19 +1,194 @@
 use std::time::Duration;
-use std::path::{PathBuf, Path};
-use std::fs::File;
+use std::net::SocketAddr;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
-use callback::Callback;
+use crate::callback::{Callback, ReadHandler};
+use crate::storage::Storage;
+use crate::remap::RemapRules;
+use crate::acl::AccessList;
+use crate::ratelimit::RateLimiter;
+use crate::flood::RequestTracker;
+use crate::packet::data;
 
 #[derive(Clone)]
 pub struct Config {
-    pub root: PathBuf,
+    pub storage: Arc<Storage>,
 
-    pub file_read_started_callback:    Option<Arc<Callback<Path, File>>>,
-    pub file_write_started_callback:   Option<Arc<Callback<Path, File>>>,
-    pub file_read_completed_callback:  Option<Arc<Callback<Path, File>>>,
-    pub file_write_completed_callback: Option<Arc<Callback<Path, File>>>,
+    pub file_read_started_callback:    Option<Arc<Callback<Path, SocketAddr>>>,
+    pub file_write_started_callback:   Option<Arc<Callback<Path, SocketAddr>>>,
+    pub file_read_completed_callback:  Option<Arc<Callback<Path, SocketAddr>>>,
+    pub file_write_completed_callback: Option<Arc<Callback<Path, SocketAddr>>>,
+    pub read_request_handler: Option<Arc<ReadHandler>>,
 
     pub read_timeout: Option<Duration>,
-    pub send_retry_attempts: u8
+    pub send_retry_attempts: u8,
+
+    pub max_block_size: usize,
+    pub max_window_size: u16,
+    pub block_rollover: u16,
+    pub max_upload_size: Option<u64>,
+    pub write_policy: WritePolicy,
+
+    pub min_timeout: Duration,
+    pub max_timeout: Duration,
+
+    pub port_range: Option<RangeInclusive<u16>>,
+
+    pub absolute_paths: AbsolutePaths,
+    pub remap_rules: Option<Arc<RemapRules>>,
+    pub access_list: Option<Arc<AccessList>>,
+
+    pub max_concurrent_transfers: Option<usize>,
+    pub max_transfers_per_client: Option<usize>,
+    pub busy_policy: BusyPolicy,
+    pub request_tracker: Option<Arc<RequestTracker>>,
+
+    pub rate_limiter: Option<Arc<RateLimiter>>
+}
+
+impl Config {
+
+    /// The configuration a new server starts with, keeping files in
+    /// `storage`
+    pub fn new(storage: Arc<Storage>) -> Config {
+        Config {
+            storage: storage,
+            file_read_started_callback:    None,
+            file_write_started_callback:   None,
+            file_read_completed_callback:  None,
+            file_write_completed_callback: None,
+            read_request_handler:          None,
+
+            read_timeout: Some(Duration::from_millis(20)),
+            send_retry_attempts: 5,
+
+            max_block_size: data::MAX_BLOCK_SIZE,
+            max_window_size: 64,
+            block_rollover: 0,
+            max_upload_size: None,
+            write_policy: WritePolicy::CreateOnly,
+
+            min_timeout: Duration::from_secs(1),
+            max_timeout: Duration::from_secs(255),
+
+            port_range: None,
+
+            absolute_paths: AbsolutePaths::Reject,
+            remap_rules: None,
+            access_list: None,
+
+            max_concurrent_transfers: None,
+            max_transfers_per_client: None,
+            busy_policy: BusyPolicy::Reject,
+            request_tracker: None,
+
+            rate_limiter: None
+        }
+    }
+}
+
+/// How a request for an absolute path is handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AbsolutePaths {
+    /// Refuse the request with an access violation
+    Reject,
+
+    /// Treat the path as relative to the server root
+    MapUnderRoot
+}
+
+/// What happens to a request that arrives when the server is already
+/// running as many transfers as it is allowed to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusyPolicy {
+    /// Refuse the request with an error, so the client can try again later
+    Reject,
+
+    /// Hold up to the given number of requests, and serve them in order as
+    /// transfers finish. Requests beyond that are refused.
+    Queue(usize)
+}
+
+/// The rates (in bytes per second) at which the server may send data. Each
+/// limit that is None is not applied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RateLimits {
+    /// The limit for all transfers together
+    pub global: Option<u64>,
+
+    /// The limit for all transfers to a single client IP address
+    pub per_client: Option<u64>,
+
+    /// The limit for each transfer
+    pub per_transfer: Option<u64>
+}
+
+/// How quickly a single client IP address may make requests. Requests are
+/// allowed at `per_second` on average, with bursts of up to `burst` at once.
+/// A client that makes requests any faster is banned, and all of its
+/// requests are ignored, for `ban`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestLimits {
+    pub per_second: u32,
+    pub burst: u32,
+    pub ban: Duration
+}
+
+/// Which write requests are accepted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WritePolicy {
+    /// Refuse every write request with an access violation
+    ReadOnly,
+
+    /// Allow new files to be written, but refuse to replace existing ones
+    CreateOnly,
+
+    /// Allow new files to be written, and existing ones to be replaced
+    Overwrite,
+
+    /// Choose a policy by directory. Each entry gives the policy for files
+    /// under a directory relative to the root, and the entry for the most
+    /// specific directory applies. Files under none of the directories are
+    /// read-only.
+    PerDirectory(Vec<(PathBuf, WritePolicy)>)
+}
+
+static READ_ONLY: WritePolicy = WritePolicy::ReadOnly;
+
+impl WritePolicy {
+
+    /// The policy that applies to the file at `path`, relative to the root.
+    /// This is never `PerDirectory`.
+    pub fn for_path(&self, path: &Path) -> &WritePolicy {
+        match *self {
+            WritePolicy::PerDirectory(ref directories) => {
+                let matching = directories.iter()
+                    .filter(|&&(ref dir, _)| path.starts_with(dir))
+                    .max_by_key(|&&(ref dir, _)| dir.components().count());
+                match matching {
+                    Some(&(_, ref policy)) => policy.for_path(path),
+                    None => &READ_ONLY
+                }
+            },
+            ref policy => policy
+        }
+    }
+}
+
+#[test]
+fn write_policy_per_directory() {
+    let policy = WritePolicy::PerDirectory(vec![
+        (PathBuf::from("uploads"), WritePolicy::CreateOnly),
+        (PathBuf::from("uploads/scratch"), WritePolicy::Overwrite),
+        (PathBuf::from("uploads/scratch/keep"), WritePolicy::ReadOnly)
+    ]);
+
+    assert_eq!(policy.for_path(Path::new("uploads/a.bin")), &WritePolicy::CreateOnly);
+    assert_eq!(policy.for_path(Path::new("uploads/scratch/a.bin")), &WritePolicy::Overwrite);
+    assert_eq!(policy.for_path(Path::new("uploads/scratch/keep/a")), &WritePolicy::ReadOnly);
+    assert_eq!(policy.for_path(Path::new("uploads-old/a.bin")), &WritePolicy::ReadOnly);
+    assert_eq!(policy.for_path(Path::new("pxelinux.0")), &WritePolicy::ReadOnly);
+    assert_eq!(WritePolicy::Overwrite.for_path(Path::new("a")), &WritePolicy::Overwrite);
 }
@@ -0,0 +1,60 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+use crate::packet::error::TftpError;
+use crate::transfer::TransferError;
+
+/// The ways in which serving or making a request can fail
+#[derive(Debug)]
+pub enum Error {
+    /// A file or socket could not be used
+    Io(io::Error),
+
+    /// The peer stopped responding
+    TimedOut,
+
+    /// The transfer could not continue on this side. The peer has been sent
+    /// this error.
+    Local(TftpError),
+
+    /// The peer refused the request or aborted the transfer
+    Remote(TftpError),
+
+    /// The peer sent a packet that does not follow the protocol
+    Protocol(String)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "{}", e),
+            Error::TimedOut => write!(f, "Timed out waiting for a response"),
+            Error::Local(ref e) => write!(f, "Transfer aborted: {:?}", e.code),
+            Error::Remote(ref e) => match e.message {
+                Some(ref message) if !message.is_empty() =>
+                    write!(f, "Peer error ({:?}): {}", e.code, message),
+                _ => write!(f, "Peer error ({:?})", e.code)
+            },
+            Error::Protocol(ref message) => write!(f, "Protocol error: {}", message)
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<TransferError> for Error {
+    fn from(e: TransferError) -> Error {
+        match e {
+            TransferError::TimedOut => Error::TimedOut,
+            TransferError::Local(e) => Error::Local(e),
+            TransferError::Remote(e) => Error::Remote(e)
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::config::RequestLimits;
+use crate::sync::lock;
+
+// Once this many clients are being tracked, the ones that are in good
+// standing are forgotten
+const PRUNE_THRESHOLD: usize = 4096;
+
+// What to do with a request, according to how many its client has made
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+
+    // The client has just gone over the limit, and is now banned
+    Ban,
+
+    // The client is still banned
+    Banned
+}
+
+// Tracks the rate at which each client makes requests, using a token bucket
+// of requests for each
+pub struct RequestTracker {
+    limits: RequestLimits,
+    clients: Mutex<HashMap<IpAddr, Client>>
+}
+
+struct Client {
+    tokens: f64,
+    updated: Instant,
+    banned_until: Option<Instant>
+}
+
+impl RequestTracker {
+    pub fn new(limits: RequestLimits) -> RequestTracker {
+        RequestTracker{
+            limits: limits,
+            clients: Mutex::new(HashMap::new())
+        }
+    }
+
+    // Record a request from `client` at `now`
+    pub fn check(&self, client: IpAddr, now: Instant) -> Verdict {
+        let rate = self.limits.per_second.max(1) as f64;
+        let capacity = self.limits.burst.max(1) as f64;
+
+        let mut clients = lock(&self.clients);
+        if clients.len() >= PRUNE_THRESHOLD && !clients.contains_key(&client) {
+            clients.retain(|_, c| c.is_limited(now, rate, capacity));
+        }
+
+        let state = clients.entry(client).or_insert(Client{
+            tokens: capacity,
+            updated: now,
+            banned_until: None
+        });
+
+        if let Some(until) = state.banned_until {
+            if now < until {
+                return Verdict::Banned;
+            }
+            state.banned_until = None;
+            state.tokens = capacity;
+            state.updated = now;
+        }
+
+        let elapsed = now.saturating_duration_since(state.updated).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * rate).min(capacity);
+        state.updated = now;
+        if state.tokens < 1.0 {
+            state.banned_until = Some(now + self.limits.ban);
+            return Verdict::Ban;
+        }
+        state.tokens -= 1.0;
+        Verdict::Allow
+    }
+}
+
+impl Client {
+    // Whether the client is banned, or has made requests recently enough
+    // that its bucket hasn't refilled, so it must still be tracked
+    fn is_limited(&self, now: Instant, rate: f64, capacity: f64) -> bool {
+        if self.banned_until.map_or(false, |until| now < until) {
+            return true;
+        }
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens + elapsed * rate < capacity
+    }
+}
+
+#[test]
+fn request_tracker_bans_flooding_clients() {
+    use std::time::Duration;
+
+    let tracker = RequestTracker::new(RequestLimits{
+        per_second: 10,
+        burst: 3,
+        ban: Duration::from_secs(60)
+    });
+    let flooder: IpAddr = "192.0.2.1".parse().unwrap();
+    let other: IpAddr = "192.0.2.2".parse().unwrap();
+    let start = Instant::now();
+
+    for _ in 0..3 {
+        assert_eq!(tracker.check(flooder, start), Verdict::Allow);
+    }
+    assert_eq!(tracker.check(flooder, start), Verdict::Ban);
+    assert_eq!(tracker.check(other, start), Verdict::Allow);
+
+    // The ban outlasts the rate limit, but does end
+    let later = start + Duration::from_secs(30);
+    assert_eq!(tracker.check(flooder, later), Verdict::Banned);
+    let after = start + Duration::from_secs(61);
+    assert_eq!(tracker.check(flooder, after), Verdict::Allow);
+
+    // Requests at the average rate are fine
+    for i in 0..20 {
+        assert_eq!(tracker.check(other, start + Duration::from_millis(100 * i)), Verdict::Allow);
+    }
+}
@@ -1,6 +1,31 @@
+extern crate libc;
+extern crate regex;
+#[macro_use]
+extern crate log;
+
 pub mod server;
+pub mod client;
+pub mod storage;
+pub mod remap;
+pub mod acl;
 mod packet;
 mod codes;
 mod transfer;
 mod callback;
 mod config;
+mod options;
+mod netascii;
+mod resolve;
+mod cidr;
+mod error;
+mod socket;
+mod ratelimit;
+mod flood;
+mod pktinfo;
+mod sync;
+
+pub use codes::{ErrorCode, TransferMode};
+pub use packet::error::TftpError;
+pub use error::Error;
+pub use config::{AbsolutePaths, BusyPolicy, RateLimits, RequestLimits, WritePolicy};
+pub use cidr::{Cidr, ParseCidrError};
@@ -0,0 +1,197 @@
+use std::io;
+use std::io::{Read, Write};
+
+const CR: u8 = b'\r';
+const LF: u8 = b'\n';
+const NUL: u8 = 0;
+
+/// Translates a stream of local text into netascii (RFC 764) as it is read.
+/// Line feeds become CR LF, and carriage returns become CR NUL.
+pub struct NetAsciiEncoder<R> {
+    inner: R,
+
+    // The second byte of a translated sequence that did not fit in the
+    // caller's buffer
+    pending: Option<u8>
+}
+
+impl<R: Read> NetAsciiEncoder<R> {
+    pub fn new(inner: R) -> NetAsciiEncoder<R> {
+        NetAsciiEncoder{
+            inner: inner,
+            pending: None
+        }
+    }
+}
+
+impl<R: Read> Read for NetAsciiEncoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let mut written = 0;
+        if let Some(byte) = self.pending.take() {
+            buf[0] = byte;
+            written = 1;
+        }
+
+        // Every byte translates to at most two, so reading half of the
+        // remaining space can overflow by at most one byte
+        let remaining = buf.len() - written;
+        if remaining == 0 {
+            return Ok(written);
+        }
+        let mut input = vec![0u8; (remaining / 2) + (remaining % 2)];
+        let count = self.inner.read(&mut input)?;
+
+        for &byte in &input[..count] {
+            let (first, second) = match byte {
+                LF => (CR, Some(LF)),
+                CR => (CR, Some(NUL)),
+                other => (other, None)
+            };
+
+            buf[written] = first;
+            written += 1;
+            if let Some(second) = second {
+                if written < buf.len() {
+                    buf[written] = second;
+                    written += 1;
+                } else {
+                    self.pending = Some(second);
+                }
+            }
+        }
+        Ok(written)
+    }
+}
+
+/// Translates a stream of netascii (RFC 764) into local text as it is
+/// written. CR LF becomes a line feed, and CR NUL a carriage return.
+/// `finish` must be called once the whole stream has been written.
+pub struct NetAsciiDecoder<W: Write> {
+    inner: W,
+
+    // Whether the last byte written was a CR, the meaning of which depends
+    // on the next byte
+    pending_cr: bool
+}
+
+impl<W: Write> NetAsciiDecoder<W> {
+    pub fn new(inner: W) -> NetAsciiDecoder<W> {
+        NetAsciiDecoder{
+            inner: inner,
+            pending_cr: false
+        }
+    }
+
+    /// Write out anything still held back at the end of the stream, and
+    /// return the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        if self.pending_cr {
+            self.inner.write_all(&[CR])?;
+            self.pending_cr = false;
+        }
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for NetAsciiDecoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut output = Vec::with_capacity(buf.len() + 1);
+        for &byte in buf {
+            if self.pending_cr {
+                self.pending_cr = false;
+                match byte {
+                    LF => output.push(LF),
+                    NUL => output.push(CR),
+
+                    // Not valid netascii, so pass it through untouched
+                    CR => {
+                        output.push(CR);
+                        self.pending_cr = true;
+                    },
+                    other => {
+                        output.push(CR);
+                        output.push(other);
+                    }
+                }
+            } else if byte == CR {
+                self.pending_cr = true;
+            } else {
+                output.push(byte);
+            }
+        }
+
+        self.inner.write_all(&output)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Returns the size `reader` will have once it has been translated into
+/// netascii, consuming the reader.
+pub fn encoded_size<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut buffer = [0u8; 8192];
+    let mut size = 0;
+    loop {
+        let count = match reader.read(&mut buffer) {
+            Ok(0) => return Ok(size),
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e)
+        };
+        size += count as u64;
+        size += buffer[..count].iter().filter(|&&b| b == CR || b == LF).count() as u64;
+    }
+}
+
+#[cfg(test)]
+fn encode_in_chunks(input: &[u8], chunk: usize) -> Vec<u8> {
+    let mut encoder = NetAsciiEncoder::new(input);
+    let mut output = vec![];
+    let mut buffer = vec![0u8; chunk];
+    loop {
+        match encoder.read(&mut buffer).unwrap() {
+            0 => return output,
+            n => output.extend(&buffer[..n])
+        }
+    }
+}
+
+#[test]
+fn netascii_encode() {
+    let input = b"line one\nline\rtwo\r\n";
+    let expected = b"line one\r\nline\r\0two\r\0\r\n".to_vec();
+
+    // Small buffers split the translated sequences
+    for chunk in 1..8 {
+        assert_eq!(encode_in_chunks(input, chunk), expected);
+    }
+    assert_eq!(encoded_size(&mut &input[..]).unwrap(), expected.len() as u64);
+}
+
+#[test]
+fn netascii_decode() {
+    let input = b"line one\r\nline\r\0two\r\0\r\n";
+    let expected = b"line one\nline\rtwo\r\n".to_vec();
+
+    // Writes that end in the middle of a sequence
+    for chunk in 1..8 {
+        let mut decoder = NetAsciiDecoder::new(vec![]);
+        for part in input.chunks(chunk) {
+            decoder.write_all(part).unwrap();
+        }
+        assert_eq!(decoder.finish().unwrap(), expected);
+    }
+
+    // A trailing CR is kept
+    let mut decoder = NetAsciiDecoder::new(vec![]);
+    decoder.write_all(b"end\r").unwrap();
+    assert_eq!(decoder.finish().unwrap(), b"end\r".to_vec());
+}
@@ -0,0 +1,279 @@
+use std::time::Duration;
+use std::cmp;
+
+use crate::config::Config;
+use crate::packet::data;
+
+/// The options in effect for a single transfer, as negotiated with the
+/// client (RFC 2347).
+#[derive(Debug, Clone)]
+pub struct TransferOptions {
+    /// The number of data bytes carried by each DATA packet (RFC 2348)
+    pub block_size: usize,
+
+    /// The size of the file being transferred, if the client asked for it.
+    /// For a write request this is the size declared by the client, for a
+    /// read request it is only a placeholder (RFC 2349).
+    pub transfer_size: Option<u64>,
+
+    /// The retransmission timeout requested by the client, if any (RFC 2349)
+    pub timeout: Option<Duration>,
+
+    /// The number of DATA packets sent before waiting for an ACK (RFC 7440)
+    pub window_size: usize,
+
+    /// The block number that follows block 65535, either 0 or 1
+    pub rollover: u16,
+
+    /// The options accepted by the server and the values they were accepted
+    /// with. If this is not empty, these are sent to the client in an OACK.
+    pub acknowledged: Vec<(String, String)>
+}
+
+impl TransferOptions {
+
+    /// Negotiate the options requested by a client, within the limits set by
+    /// `config`. Options the server does not understand are ignored, as
+    /// required by RFC 2347.
+    pub fn negotiate(config: &Config, requested: &[(String, String)]) -> TransferOptions {
+        let mut options = TransferOptions{
+            rollover: config.block_rollover,
+            ..TransferOptions::default()
+        };
+
+        for &(ref name, ref value) in requested {
+            if let Some(accepted) = options.accept(config, name, value) {
+                options.acknowledged.push((name.clone(), accepted));
+            }
+        }
+        options
+    }
+
+    /// Determine the options for a transfer from the OACK a server sent in
+    /// response to the `requested` options. Returns None if the server
+    /// acknowledged an option that was not requested, or with a value the
+    /// client cannot accept.
+    pub fn from_oack(requested: &[(String, String)], acknowledged: &[(String, String)])
+                     -> Option<TransferOptions> {
+        let mut options = TransferOptions::default();
+        for &(ref name, ref value) in acknowledged {
+            let request = match requested.iter().find(|&&(ref n, _)| n == name) {
+                Some(&(_, ref v)) => v,
+                None => return None
+            };
+
+            match &name[..] {
+                // The server may only lower the block and window sizes
+                "blksize" => match (value.parse::<usize>(), request.parse::<usize>()) {
+                    (Ok(size), Ok(max)) if size >= data::MIN_BLOCK_SIZE && size <= max =>
+                        options.block_size = size,
+                    _ => return None
+                },
+                "windowsize" => match (value.parse::<usize>(), request.parse::<usize>()) {
+                    (Ok(size), Ok(max)) if size >= 1 && size <= max =>
+                        options.window_size = size,
+                    _ => return None
+                },
+                "tsize" => match value.parse::<u64>() {
+                    Ok(size) => options.transfer_size = Some(size),
+                    _ => return None
+                },
+                "timeout" => match value.parse::<u64>() {
+                    Ok(secs) if value == request => options.timeout = Some(Duration::from_secs(secs)),
+                    _ => return None
+                },
+                "rollover" => match value.parse::<u16>() {
+                    Ok(first) if value == request && first <= 1 => options.rollover = first,
+                    _ => return None
+                },
+                _ => return None
+            }
+        }
+        options.acknowledged = acknowledged.to_vec();
+        Some(options)
+    }
+
+    // Apply a single requested option. Returns the value to acknowledge the
+    // option with, or None if the option is not supported or its value is
+    // invalid.
+    fn accept(&mut self, config: &Config, name: &str, value: &str) -> Option<String> {
+        match name {
+            "blksize" => {
+                let requested = match value.parse::<usize>() {
+                    Ok(size) if size >= data::MIN_BLOCK_SIZE => size,
+                    _ => return None
+                };
+
+                // The server may reply with a smaller block size than was
+                // requested, which the client must then use
+                self.block_size = cmp::min(requested, cmp::min(config.max_block_size,
+                                                               data::MAX_BLOCK_SIZE));
+                Some(self.block_size.to_string())
+            },
+            "tsize" => {
+                // The acknowledged value depends on the file being
+                // transferred, so it is added to the OACK by the transfer
+                // itself
+                if let Ok(size) = value.parse::<u64>() {
+                    self.transfer_size = Some(size);
+                }
+                None
+            },
+            "timeout" => {
+                let requested = match value.parse::<u64>() {
+                    Ok(secs) if secs >= 1 && secs <= 255 => Duration::from_secs(secs),
+                    _ => return None
+                };
+
+                // The acknowledged timeout must match the one the client
+                // requested, so one outside the server's bounds is refused,
+                // leaving both sides on the default timeout
+                if requested < config.min_timeout || requested > config.max_timeout {
+                    return None;
+                }
+                self.timeout = Some(requested);
+                Some(value.to_string())
+            },
+            "windowsize" => {
+                let requested = match value.parse::<usize>() {
+                    Ok(size) if size >= 1 && size <= 65535 => size,
+                    _ => return None
+                };
+
+                // As with the block size, the server may reply with a smaller
+                // window than was requested
+                self.window_size = cmp::min(requested, config.max_window_size as usize);
+                Some(self.window_size.to_string())
+            },
+            "rollover" => {
+                // This is not a standard option, but is widely supported
+                // for transfers of more than 65535 blocks
+                match value.parse::<u16>() {
+                    Ok(first) if first <= 1 => {
+                        self.rollover = first;
+                        Some(value.to_string())
+                    },
+                    _ => None
+                }
+            },
+            _ => None
+        }
+    }
+
+    /// Returns the number of the block that follows block `number`. Block
+    /// numbers wrap around to `rollover` after 65535.
+    pub fn next_block(&self, number: u16) -> u16 {
+        if number == u16::max_value() {
+            self.rollover
+        } else {
+            number + 1
+        }
+    }
+}
+
+impl Default for TransferOptions {
+
+    /// The options in effect when none are negotiated (RFC 1350)
+    fn default() -> TransferOptions {
+        TransferOptions{
+            block_size: data::DEFAULT_BLOCK_SIZE,
+            transfer_size: None,
+            timeout: None,
+            window_size: 1,
+            rollover: 0,
+            acknowledged: vec![]
+        }
+    }
+}
+
+#[test]
+fn block_numbers_roll_over() {
+    let mut options = TransferOptions::default();
+    assert_eq!(options.next_block(1), 2);
+    assert_eq!(options.next_block(65535), 0);
+
+    options.rollover = 1;
+    assert_eq!(options.next_block(65535), 1);
+}
+
+#[test]
+fn oack_must_match_request() {
+    let requested = vec![("blksize".to_string(), "1428".to_string()),
+                         ("tsize".to_string(), "0".to_string())];
+
+    let acknowledged = vec![("blksize".to_string(), "1024".to_string()),
+                            ("tsize".to_string(), "4096".to_string())];
+    let options = TransferOptions::from_oack(&requested, &acknowledged).unwrap();
+    assert_eq!(options.block_size, 1024);
+    assert_eq!(options.transfer_size, Some(4096));
+
+    // A larger block size than was requested
+    let acknowledged = vec![("blksize".to_string(), "2048".to_string())];
+    assert!(TransferOptions::from_oack(&requested, &acknowledged).is_none());
+
+    // An option that was never requested
+    let acknowledged = vec![("windowsize".to_string(), "4".to_string())];
+    assert!(TransferOptions::from_oack(&requested, &acknowledged).is_none());
+}
+
+// A request for the single option `name`, with `value`
+#[cfg(test)]
+fn request(name: &str, value: &str) -> Vec<(String, String)> {
+    vec![(name.to_string(), value.to_string())]
+}
+
+#[test]
+fn block_size_is_clamped() {
+    use std::sync::Arc;
+    use crate::storage::MemoryStorage;
+
+    let mut config = Config::new(Arc::new(MemoryStorage::new()));
+    config.max_block_size = 1428;
+    let negotiate = |config: &Config, size| TransferOptions::negotiate(config, &request("blksize", size));
+
+    let options = negotiate(&config, "1024");
+    assert_eq!(options.block_size, 1024);
+    assert_eq!(options.acknowledged, request("blksize", "1024"));
+    assert_eq!(negotiate(&config, "8").block_size, 8);
+
+    // Larger blocks are cut down to the server's limit, which the client
+    // is told
+    let options = negotiate(&config, "8192");
+    assert_eq!(options.block_size, 1428);
+    assert_eq!(options.acknowledged, request("blksize", "1428"));
+
+    // Which can't be more than fits in a UDP packet
+    config.max_block_size = 100000;
+    assert_eq!(negotiate(&config, "65535").block_size, data::MAX_BLOCK_SIZE);
+
+    // Blocks smaller than 8 bytes aren't allowed, so the option is ignored
+    for size in &["7", "0", "-512", "big"] {
+        let options = negotiate(&config, size);
+        assert_eq!(options.block_size, data::DEFAULT_BLOCK_SIZE);
+        assert!(options.acknowledged.is_empty());
+    }
+}
+
+#[test]
+fn timeout_is_bounded() {
+    use std::sync::Arc;
+    use crate::storage::MemoryStorage;
+
+    let mut config = Config::new(Arc::new(MemoryStorage::new()));
+    config.min_timeout = Duration::from_secs(2);
+    config.max_timeout = Duration::from_secs(10);
+    let negotiate = |secs| TransferOptions::negotiate(&config, &request("timeout", secs));
+
+    let options = negotiate("5");
+    assert_eq!(options.timeout, Some(Duration::from_secs(5)));
+    assert_eq!(options.acknowledged, request("timeout", "5"));
+
+    // A timeout outside the server's bounds can't be acknowledged, as the
+    // client must get back what it asked for, so the default is used instead.
+    // Timeouts of 1 to 255 seconds are all that can be asked for at all.
+    for secs in &["1", "30", "0", "256", "soon"] {
+        let options = negotiate(secs);
+        assert_eq!(options.timeout, None);
+        assert!(options.acknowledged.is_empty());
+    }
+}
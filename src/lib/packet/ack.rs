@@ -1,4 +1,4 @@
-use packet::Packet;
+use crate::packet::Packet;
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct TftpAck {
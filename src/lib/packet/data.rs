@@ -1,4 +1,4 @@
-use packet::Packet;
+use crate::packet::Packet;
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct TftpData {
@@ -6,7 +6,14 @@ pub struct TftpData {
     pub data: Vec<u8>,
 }
 
-pub const MAX_DATA_SIZE: usize = 512;
+/// The block size used when none is negotiated (RFC 1350)
+pub const DEFAULT_BLOCK_SIZE: usize = 512;
+
+/// The smallest block size that may be negotiated (RFC 2348)
+pub const MIN_BLOCK_SIZE: usize = 8;
+
+/// The largest block size that may be negotiated (RFC 2348)
+pub const MAX_BLOCK_SIZE: usize = 65464;
 
 impl Packet for TftpData {
     fn as_packet(&self) -> Vec<u8> {
@@ -35,7 +42,7 @@ impl Packet for TftpData {
 fn tftp_data_round_trip() {
     let data = TftpData{
         number: 1u16,
-        data: vec![0u8; MAX_DATA_SIZE]
+        data: vec![0u8; DEFAULT_BLOCK_SIZE]
     };
     let roundtrip = TftpData::from_buffer(&data.as_packet()).unwrap();
 
@@ -2,11 +2,11 @@ use std::io::ErrorKind;
 use std::iter::FromIterator;
 use std::slice::Iter;
 
-use packet::Packet;
-use codes::{ErrorCode, Opcode};
+use crate::packet::Packet;
+use crate::codes::{ErrorCode, Opcode};
 
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct TftpError {
     pub code: ErrorCode,
     pub message: Option<String>,
@@ -34,6 +34,8 @@ impl Packet for TftpError {
                 (6u8, message.unwrap_or("File exists".to_string())),
             ErrorCode::NoSuchUser =>
                 (7u8, message.unwrap_or("No such user".to_string())),
+            ErrorCode::OptionNegotiation =>
+                (8u8, message.unwrap_or("Option negotiation failed".to_string())),
         };
 
         // The specific error code
@@ -65,6 +67,7 @@ impl Packet for TftpError {
             5u8 => ErrorCode::UnknownTransferID,
             6u8 => ErrorCode::FileExists,
             7u8 => ErrorCode::NoSuchUser,
+            8u8 => ErrorCode::OptionNegotiation,
             _ => return None
         };
 
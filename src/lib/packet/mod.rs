@@ -1,13 +1,17 @@
 pub mod data;
 pub mod ack;
 pub mod error;
+pub mod oack;
+pub mod request;
 
-use packet::error::TftpError;
-use codes::{Opcode, ErrorCode};
+use std::str;
+
+use crate::packet::error::TftpError;
+use crate::codes::{Opcode, ErrorCode};
 
 pub trait Packet: Sized {
     fn as_packet(&self) -> Vec<u8>;
-    fn from_buffer(&[u8]) -> Option<Self>;
+    fn from_buffer(buf: &[u8]) -> Option<Self>;
 }
 
 pub type PacketBuff = [u8; 1024];
@@ -29,6 +33,7 @@ pub fn get_packet_opcode(length: usize, packet: &PacketBuff) -> Result<Opcode, T
         3 => Ok(Opcode::Data),
         4 => Ok(Opcode::Acknowledgment),
         5 => Ok(Opcode::Error),
+        6 => Ok(Opcode::OptionAcknowledgment),
         _ => {
             Err(TftpError {
                 code: ErrorCode::Undefined,
@@ -37,3 +42,41 @@ pub fn get_packet_opcode(length: usize, packet: &PacketBuff) -> Result<Opcode, T
         }
     }
 }
+
+// Parse a sequence of NULL terminated option/value pairs (RFC 2347), as
+// found at the end of a RRQ/WRQ or in an OACK. Option names are case
+// insensitive, so they are normalized to lowercase. Returns None if the
+// buffer is not a well formed list of pairs.
+pub fn parse_options(buf: &[u8]) -> Option<Vec<(String, String)>> {
+    let mut options = vec![];
+    if buf.is_empty() {
+        return Some(options);
+    } else if buf[buf.len()-1] != 0u8 {
+        return None;
+    }
+
+    let mut fields = buf[..buf.len()-1].split(|x| *x == 0);
+    while let Some(name) = fields.next() {
+        let value = match fields.next() {
+            Some(v) => v,
+            None => return None
+        };
+
+        match (str::from_utf8(name), str::from_utf8(value)) {
+            (Ok(n), Ok(v)) => options.push((n.to_lowercase(), v.to_string())),
+            _ => return None
+        }
+    }
+    Some(options)
+}
+
+#[test]
+fn parse_options_pairs() {
+    let options = parse_options(b"BlkSize\x001024\x00tsize\x000\x00").unwrap();
+    assert_eq!(options, vec![("blksize".to_string(), "1024".to_string()),
+                             ("tsize".to_string(), "0".to_string())]);
+
+    assert_eq!(parse_options(b"").unwrap(), vec![]);
+    assert!(parse_options(b"blksize\x00").is_none());
+    assert!(parse_options(b"blksize\x001024").is_none());
+}
@@ -0,0 +1,42 @@
+use crate::packet::{Packet, parse_options};
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct TftpOack {
+    pub options: Vec<(String, String)>
+}
+
+impl Packet for TftpOack {
+    fn as_packet(&self) -> Vec<u8> {
+        // The OACK Opcode, always 6
+        let mut packet = vec![0u8, 6u8];
+        for &(ref name, ref value) in &self.options {
+            packet.extend(name.bytes());
+            packet.push(0u8);
+            packet.extend(value.bytes());
+            packet.push(0u8);
+        }
+        packet
+    }
+
+    fn from_buffer(buf: &[u8]) -> Option<TftpOack> {
+        if buf.len() < 2 {
+            return None
+        } else if buf[0] != 0u8 || buf[1] != 6u8 {
+            return None
+        }
+
+        parse_options(&buf[2..]).map(|options| TftpOack{
+            options: options
+        })
+    }
+}
+
+#[test]
+fn tftp_oack_round_trip() {
+    let oack = TftpOack{
+        options: vec![("blksize".to_string(), "1428".to_string())]
+    };
+    let roundtrip = TftpOack::from_buffer(&oack.as_packet()).unwrap();
+
+    assert_eq!(oack, roundtrip);
+}
@@ -0,0 +1,88 @@
+use crate::packet::{Packet, parse_options};
+use crate::codes::{Opcode, TransferMode};
+
+use std::str;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct TftpRequest {
+    /// Either `ReadRequest` or `WriteRequest`
+    pub opcode: Opcode,
+    pub filename: String,
+    pub mode: TransferMode,
+    pub options: Vec<(String, String)>
+}
+
+impl Packet for TftpRequest {
+    fn as_packet(&self) -> Vec<u8> {
+        let opcode = match self.opcode {
+            Opcode::WriteRequest => 2u8,
+            _ => 1u8
+        };
+        let mode = match self.mode {
+            TransferMode::NetAscii => "netascii",
+            TransferMode::Octet => "octet"
+        };
+
+        let mut packet = vec![0u8, opcode];
+        packet.extend(self.filename.bytes());
+        packet.push(0u8);
+        packet.extend(mode.bytes());
+        packet.push(0u8);
+        for &(ref name, ref value) in &self.options {
+            packet.extend(name.bytes());
+            packet.push(0u8);
+            packet.extend(value.bytes());
+            packet.push(0u8);
+        }
+        packet
+    }
+
+    fn from_buffer(buf: &[u8]) -> Option<TftpRequest> {
+        if buf.len() < 2 || buf[0] != 0u8 {
+            return None
+        }
+        let opcode = match buf[1] {
+            1u8 => Opcode::ReadRequest,
+            2u8 => Opcode::WriteRequest,
+            _ => return None
+        };
+
+        let mut parts = buf[2..].splitn(3, |x| *x == 0);
+        let filename = match parts.next().map(str::from_utf8) {
+            Some(Ok(f)) => f.to_string(),
+            _ => return None
+        };
+        let mode = match parts.next().map(str::from_utf8) {
+            Some(Ok(m)) => match &m.to_lowercase()[..] {
+                "netascii" => TransferMode::NetAscii,
+                "octet" => TransferMode::Octet,
+                _ => return None
+            },
+            _ => return None
+        };
+        let options = match parse_options(parts.next().unwrap_or(&[])) {
+            Some(o) => o,
+            None => return None
+        };
+
+        Some(TftpRequest{
+            opcode: opcode,
+            filename: filename,
+            mode: mode,
+            options: options
+        })
+    }
+}
+
+#[test]
+fn tftp_request_round_trip() {
+    let request = TftpRequest{
+        opcode: Opcode::WriteRequest,
+        filename: "pxelinux.0".to_string(),
+        mode: TransferMode::Octet,
+        options: vec![("tsize".to_string(), "26140".to_string())]
+    };
+    let roundtrip = TftpRequest::from_buffer(&request.as_packet()).unwrap();
+
+    assert_eq!(request, roundtrip);
+}
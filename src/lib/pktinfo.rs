@@ -0,0 +1,187 @@
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+
+// Ask for the local address each packet was sent to to be reported along
+// with it, which is needed to tell which address a client contacted when the
+// socket is bound to a wildcard address
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn enable(socket: &UdpSocket) -> io::Result<()> {
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+
+    // An IPv6 socket also reports IPv4 packets' addresses this way, as
+    // IPv4-mapped addresses
+    let (level, option) = match socket.local_addr()? {
+        SocketAddr::V4(_) => (libc::IPPROTO_IP, libc::IP_PKTINFO),
+        SocketAddr::V6(_) => (libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO)
+    };
+    let on: libc::c_int = 1;
+    let result = unsafe {
+        libc::setsockopt(socket.as_raw_fd(), level, option,
+                         &on as *const libc::c_int as *const libc::c_void,
+                         mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn enable(_: &UdpSocket) -> io::Result<()> {
+    Ok(())
+}
+
+// Receive a packet, as with `UdpSocket::recv_from`, along with the local
+// address it was sent to. The local address has port 0 and, for IPv6, the
+// receiving interface as its scope, so that a socket can be bound to it even
+// if it is link-local. It is None if `enable` hasn't been called for the
+// socket, or it isn't supported on this platform.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn recv_from(socket: &UdpSocket, buf: &mut [u8])
+                 -> io::Result<(usize, SocketAddr, Option<SocketAddr>)> {
+    use std::mem;
+    use std::ptr;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV6};
+    use std::os::unix::io::AsRawFd;
+
+    unsafe {
+        let mut source: libc::sockaddr_storage = mem::zeroed();
+        let mut iov = libc::iovec{
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len()
+        };
+        // Aligned for the control message headers
+        let mut control = [0u64; 16];
+
+        let mut message: libc::msghdr = mem::zeroed();
+        message.msg_name = &mut source as *mut libc::sockaddr_storage as *mut libc::c_void;
+        message.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        message.msg_iov = &mut iov;
+        message.msg_iovlen = 1;
+        message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        message.msg_controllen = mem::size_of_val(&control) as _;
+
+        let count = libc::recvmsg(socket.as_raw_fd(), &mut message, 0);
+        if count < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut local = None;
+        let mut header = libc::CMSG_FIRSTHDR(&message);
+        while !header.is_null() {
+            let data = libc::CMSG_DATA(header);
+            match ((*header).cmsg_level, (*header).cmsg_type) {
+                (libc::IPPROTO_IP, libc::IP_PKTINFO) => {
+                    // The address replies should come from, which is the
+                    // receiving interface's own address for broadcasts
+                    let info = ptr::read_unaligned(data as *const libc::in_pktinfo);
+                    let addr = Ipv4Addr::from(u32::from_be(info.ipi_spec_dst.s_addr));
+                    local = Some(SocketAddr::from((addr, 0)));
+                },
+                (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO) => {
+                    let info = ptr::read_unaligned(data as *const libc::in6_pktinfo);
+                    let addr = Ipv6Addr::from(info.ipi6_addr.s6_addr);
+                    if !addr.is_multicast() {
+                        let scope = info.ipi6_ifindex as u32;
+                        local = Some(SocketAddr::V6(SocketAddrV6::new(addr, 0, 0, scope)));
+                    }
+                },
+                _ => ()
+            }
+            header = libc::CMSG_NXTHDR(&message, header);
+        }
+
+        Ok((count as usize, to_socket_addr(&source)?, local))
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn recv_from(socket: &UdpSocket, buf: &mut [u8])
+                 -> io::Result<(usize, SocketAddr, Option<SocketAddr>)> {
+    let (count, addr) = socket.recv_from(buf)?;
+    Ok((count, addr, None))
+}
+
+// Convert an address filled in by the kernel
+#[cfg(any(target_os = "linux", target_os = "android"))]
+unsafe fn to_socket_addr(storage: &libc::sockaddr_storage) -> io::Result<SocketAddr> {
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV6};
+
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let addr = &*(storage as *const libc::sockaddr_storage as *const libc::sockaddr_in);
+            let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+            Ok(SocketAddr::from((ip, u16::from_be(addr.sin_port))))
+        },
+        libc::AF_INET6 => {
+            let addr = &*(storage as *const libc::sockaddr_storage as *const libc::sockaddr_in6);
+            let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+            Ok(SocketAddr::V6(SocketAddrV6::new(ip, u16::from_be(addr.sin6_port),
+                                                addr.sin6_flowinfo, addr.sin6_scope_id)))
+        },
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Unknown address family"))
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[test]
+fn recv_from_reports_local_address() {
+    let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
+    enable(&socket).unwrap();
+    let port = socket.local_addr().unwrap().port();
+
+    // The whole of 127.0.0.0/8 is routed to the loopback interface
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.send_to(b"hello", ("127.0.0.2", port)).unwrap();
+
+    let mut buf = [0; 16];
+    let (count, addr, local) = recv_from(&socket, &mut buf).unwrap();
+    assert_eq!(&buf[..count], b"hello");
+    assert_eq!(addr, client.local_addr().unwrap());
+    assert_eq!(local, Some("127.0.0.2:0".parse().unwrap()));
+}
+
+// A link-local IPv6 address of this host and the index of its interface,
+// if it has one
+#[cfg(all(test, any(target_os = "linux", target_os = "android")))]
+fn link_local_address() -> Option<(std::net::Ipv6Addr, u32)> {
+    use std::fs;
+
+    let interfaces = fs::read_to_string("/proc/net/if_inet6").ok()?;
+    interfaces.lines().filter_map(|line| {
+        let mut fields = line.split_whitespace();
+        let addr = u128::from_str_radix(fields.next()?, 16).ok()?;
+        let index = u32::from_str_radix(fields.next()?, 16).ok()?;
+        Some((std::net::Ipv6Addr::from(addr), index))
+    }).find(|&(addr, _)| addr.segments()[0] & 0xffc0 == 0xfe80)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[test]
+fn recv_from_reports_ipv6_scope() {
+    use std::net::{Ipv6Addr, SocketAddrV6};
+    use crate::socket::bind_in_range;
+
+    // Transfers are bound to the local address, which for a link-local
+    // address needs the interface the request arrived on
+    let (ip, index) = match link_local_address() {
+        Some(a) => a,
+        None => (Ipv6Addr::LOCALHOST, 1)
+    };
+    let socket = match UdpSocket::bind("[::]:0") {
+        Ok(s) => s,
+        Err(_) => return
+    };
+    enable(&socket).unwrap();
+    let port = socket.local_addr().unwrap().port();
+
+    let client = UdpSocket::bind(SocketAddrV6::new(ip, 0, 0, index)).unwrap();
+    client.send_to(b"hello", SocketAddrV6::new(ip, port, 0, index)).unwrap();
+
+    let mut buf = [0; 16];
+    let (_, _, local) = recv_from(&socket, &mut buf).unwrap();
+    let local = local.unwrap();
+    assert_eq!(local, SocketAddr::V6(SocketAddrV6::new(ip, 0, 0, index)));
+    bind_in_range(local, None).unwrap();
+}
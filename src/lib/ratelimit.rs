@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use crate::config::RateLimits;
+use crate::sync::lock;
+
+// How long (in ms) a bucket can save up for, which is also the longest burst
+// a sender can make after being idle
+const BURST_INTERVAL: u64 = 50;
+
+// A token bucket, holding bytes that refill at `rate` bytes per second up to
+// `capacity`. Sending takes bytes from the bucket, and a sender that takes
+// more than it holds must wait for the debt to be refilled.
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    state: Mutex<BucketState>
+}
+
+struct BucketState {
+    tokens: f64,
+    updated: Instant
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> TokenBucket {
+        let rate = rate.max(1) as f64;
+        let capacity = rate * BURST_INTERVAL as f64 / 1000.0;
+        TokenBucket{
+            rate: rate,
+            capacity: capacity,
+            state: Mutex::new(BucketState{
+                tokens: capacity,
+                updated: Instant::now()
+            })
+        }
+    }
+
+    // Take `amount` bytes from the bucket. Returns how long the sender must
+    // wait before sending them.
+    pub fn take(&self, amount: usize) -> Duration {
+        let mut state = lock(&self.state);
+        let now = Instant::now();
+        let refilled = now.duration_since(state.updated).as_secs_f64() * self.rate;
+        state.tokens = (state.tokens + refilled).min(self.capacity) - amount as f64;
+        state.updated = now;
+
+        if state.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-state.tokens / self.rate)
+        }
+    }
+}
+
+// The buckets shared by the transfers of a server
+pub struct RateLimiter {
+    limits: RateLimits,
+    global: Option<Arc<TokenBucket>>,
+
+    // The bucket for each client with transfers in progress. Buckets are
+    // dropped along with the last transfer using them.
+    clients: Mutex<HashMap<IpAddr, Weak<TokenBucket>>>
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> RateLimiter {
+        RateLimiter{
+            limits: limits,
+            global: limits.global.map(|rate| Arc::new(TokenBucket::new(rate))),
+            clients: Mutex::new(HashMap::new())
+        }
+    }
+
+    // Get the throttle for a new transfer to `client`, which takes from the
+    // global bucket, the client's bucket and a bucket of its own
+    pub fn throttle(&self, client: IpAddr) -> Throttle {
+        let mut buckets = vec![];
+        if let Some(ref bucket) = self.global {
+            buckets.push(bucket.clone());
+        }
+
+        if let Some(rate) = self.limits.per_client {
+            let mut clients = lock(&self.clients);
+            let bucket = match clients.get(&client).and_then(|b| b.upgrade()) {
+                Some(b) => b,
+                None => {
+                    clients.retain(|_, b| b.strong_count() > 0);
+                    let bucket = Arc::new(TokenBucket::new(rate));
+                    clients.insert(client, Arc::downgrade(&bucket));
+                    bucket
+                }
+            };
+            buckets.push(bucket);
+        }
+
+        if let Some(rate) = self.limits.per_transfer {
+            buckets.push(Arc::new(TokenBucket::new(rate)));
+        }
+        Throttle{
+            buckets: buckets
+        }
+    }
+}
+
+// Paces the packets sent by a single transfer
+pub struct Throttle {
+    buckets: Vec<Arc<TokenBucket>>
+}
+
+impl Throttle {
+
+    // How long to wait before sending a packet of `size` bytes, which is as
+    // long as the most limited bucket needs
+    pub fn delay(&self, size: usize) -> Duration {
+        self.buckets.iter()
+            .map(|b| b.take(size))
+            .max()
+            .unwrap_or(Duration::from_secs(0))
+    }
+}
+
+#[test]
+fn token_bucket_paces_sends() {
+    // A bucket saves up 50ms of sending, so starts with 500 bytes
+    let bucket = TokenBucket::new(10000);
+    assert_eq!(bucket.take(500), Duration::from_secs(0));
+
+    // Taking another 1000 bytes needs 100ms to pay back
+    let delay = bucket.take(1000);
+    assert!(delay > Duration::from_millis(90) && delay <= Duration::from_millis(100));
+}
+
+#[test]
+fn rate_limiter_shares_client_buckets() {
+    let limiter = RateLimiter::new(RateLimits{
+        global: Some(1000000),
+        per_client: Some(1000),
+        per_transfer: Some(100000)
+    });
+    let client: IpAddr = "10.0.0.1".parse().unwrap();
+    let first = limiter.throttle(client);
+    let second = limiter.throttle(client);
+    let other = limiter.throttle("10.0.0.2".parse().unwrap());
+
+    // The client's two transfers share its 50 byte allowance
+    assert_eq!(first.delay(50), Duration::from_secs(0));
+    assert!(second.delay(50) > Duration::from_millis(40));
+    assert_eq!(other.delay(50), Duration::from_secs(0));
+
+    drop((first, second));
+    assert_eq!(limiter.throttle(client).delay(50), Duration::from_secs(0));
+}
@@ -0,0 +1,222 @@
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::net::IpAddr;
+use std::path::Path;
+
+use regex::Regex;
+
+use crate::cidr::Cidr;
+
+/// What a rule does to a filename that it matches
+#[derive(Debug, Clone)]
+pub enum Action {
+    /// Replace every match with the given text, which may refer to capture
+    /// groups as `$1` or `$name`, and carry on with the next rule
+    Map(String),
+
+    /// Stop processing rules, and serve the filename as it is now
+    Accept,
+
+    /// Refuse the request
+    Deny
+}
+
+/// A single rewrite rule, which applies to filenames matching `pattern`
+/// requested by clients in `client` (or by any client, if it is None).
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub action: Action,
+    pub pattern: Regex,
+    pub client: Option<Cidr>
+}
+
+/// An ordered list of rules used to rewrite requested filenames before they
+/// are looked up, in the manner of tftp-hpa's `--map-file`.
+#[derive(Debug, Clone, Default)]
+pub struct RemapRules {
+    rules: Vec<Rule>
+}
+
+/// The error returned when a rule file can't be parsed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// The line of the invalid rule, counting from 1
+    pub line: usize,
+    pub message: String
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl error::Error for ParseError {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
+impl RemapRules {
+
+    /// Create an empty list of rules, which leaves every filename as it is.
+    pub fn new() -> RemapRules {
+        RemapRules::default()
+    }
+
+    /// Add a rule to the end of the list.
+    pub fn push(&mut self, rule: Rule) -> &mut Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Parse a list of rules, one per line, in the form
+    ///
+    /// ```text
+    /// <action> <regex> [<replacement>] [from <address block>]
+    /// ```
+    ///
+    /// where the action is `map` (which takes a replacement), `accept` or
+    /// `deny`. A replacement of `""` removes the match. Blank lines and lines
+    /// starting with `#` are ignored. For example:
+    ///
+    /// ```text
+    /// # Some firmware uses DOS-style paths
+    /// map    \\                /
+    /// map    (?i)^pxelinux\.0$  pxelinux.0
+    /// map    ^/tftpboot/        ""
+    /// accept ^private/          from 10.0.0.0/8
+    /// deny   ^private/
+    /// ```
+    pub fn parse(text: &str) -> Result<RemapRules, ParseError> {
+        let mut rules = RemapRules::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            match parse_rule(line) {
+                Ok(rule) => rules.push(rule),
+                Err(message) => return Err(ParseError{
+                    line: index + 1,
+                    message: message
+                })
+            };
+        }
+        Ok(rules)
+    }
+
+    /// Read and parse a file of rules, as described for `parse`. A file
+    /// that can't be parsed is reported as an `InvalidData` error.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<RemapRules> {
+        let mut text = String::new();
+        let mut file = File::open(path)?;
+        file.read_to_string(&mut text)?;
+        RemapRules::parse(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Rewrite a filename requested by `client`. Returns None if the request
+    /// should be refused.
+    pub fn apply(&self, filename: &str, client: &IpAddr) -> Option<String> {
+        let mut filename = filename.to_string();
+        for rule in &self.rules {
+            let applies = match rule.client {
+                Some(ref block) => block.contains(client),
+                None => true
+            };
+            if !applies || !rule.pattern.is_match(&filename) {
+                continue;
+            }
+
+            match rule.action {
+                Action::Map(ref replacement) => {
+                    filename = rule.pattern.replace_all(&filename, &replacement[..]);
+                },
+                Action::Accept => return Some(filename),
+                Action::Deny => return None
+            }
+        }
+        Some(filename)
+    }
+}
+
+// Parse a single non-empty, non-comment line of a rule file
+fn parse_rule(line: &str) -> Result<Rule, String> {
+    let mut words: Vec<&str> = line.split_whitespace().collect();
+
+    // An address block, if given, is always at the end
+    let mut client = None;
+    if words.len() >= 2 && words[words.len() - 2] == "from" {
+        let block = words[words.len() - 1];
+        client = match block.parse::<Cidr>() {
+            Ok(c) => Some(c),
+            Err(e) => return Err(e.to_string())
+        };
+        let length = words.len() - 2;
+        words.truncate(length);
+    }
+
+    if words.len() < 2 {
+        return Err("expected an action and a pattern".to_string());
+    }
+
+    let action = match (words[0], words.len()) {
+        ("map", 3) => {
+            let replacement = match words[2] {
+                "\"\"" => "",
+                other => other
+            };
+            Action::Map(replacement.to_string())
+        },
+        ("map", _) => return Err("map takes a pattern and a replacement".to_string()),
+        ("accept", 2) => Action::Accept,
+        ("deny", 2) => Action::Deny,
+        ("accept", _) | ("deny", _) => {
+            return Err(format!("unexpected '{}' after the pattern", words[2]));
+        },
+        (other, _) => return Err(format!("unknown action '{}'", other))
+    };
+
+    let pattern = match Regex::new(words[1]) {
+        Ok(p) => p,
+        Err(e) => return Err(format!("invalid pattern: {}", e))
+    };
+
+    Ok(Rule{
+        action: action,
+        pattern: pattern,
+        client: client
+    })
+}
+
+#[test]
+fn remap_rules_rewrite_and_filter() {
+    let rules = RemapRules::parse(r#"
+        # Comments and blank lines are skipped
+
+        map    \\                /
+        map    (?i)^PXELINUX\.0$  pxelinux.0
+        map    ^/tftpboot/        ""
+        accept ^private/          from 10.0.0.0/8
+        deny   ^private/
+        map    ^(\w+)\.img$       images/$1.img
+    "#).unwrap();
+
+    let outside: IpAddr = "192.0.2.1".parse().unwrap();
+    let inside: IpAddr = "10.0.0.5".parse().unwrap();
+
+    assert_eq!(rules.apply("boot\\PXELinux.0", &outside), Some("boot/PXELinux.0".to_string()));
+    assert_eq!(rules.apply("PXELinux.0", &outside), Some("pxelinux.0".to_string()));
+    assert_eq!(rules.apply("/tftpboot/kernel.img", &outside), Some("images/kernel.img".to_string()));
+    assert_eq!(rules.apply("private/key", &outside), None);
+    assert_eq!(rules.apply("private/key", &inside), Some("private/key".to_string()));
+
+    assert_eq!(RemapRules::parse("map ^a$").unwrap_err().line, 1);
+    assert_eq!(RemapRules::parse("\ndeny x from 10.0.0.0/40").unwrap_err().line, 2);
+    assert!(RemapRules::parse("rewrite a b").is_err());
+    assert!(RemapRules::parse("deny (").is_err());
+}
@@ -0,0 +1,65 @@
+use std::path::{Component, Path, PathBuf};
+
+use crate::config::{Config, AbsolutePaths};
+use crate::codes::ErrorCode;
+use crate::packet::error::TftpError;
+
+/// Resolve the filename from a request to a path relative to the server
+/// root. Requests that would escape the root with `..` components are
+/// refused with `AccessViolation`, as are absolute paths unless they are
+/// configured to be mapped under the root.
+pub fn resolve_path<P: AsRef<Path>>(config: &Config, filename: P) -> Result<PathBuf, TftpError> {
+    match normalize(filename.as_ref(), &config.absolute_paths) {
+        Some(p) => Ok(p),
+        None => Err(TftpError{
+            code: ErrorCode::AccessViolation,
+            message: None
+        })
+    }
+}
+
+// Lexically normalize a requested filename into a path relative to the
+// server root. Returns None if the filename is empty, is absolute and
+// absolute paths are rejected, or uses `..` to climb above the root.
+fn normalize(filename: &Path, absolute_paths: &AbsolutePaths) -> Option<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in filename.components() {
+        match component {
+            Component::Prefix(_) | Component::RootDir => match *absolute_paths {
+                AbsolutePaths::Reject => return None,
+                AbsolutePaths::MapUnderRoot => ()
+            },
+            Component::CurDir => (),
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    return None;
+                }
+            },
+            Component::Normal(part) => normalized.push(part)
+        }
+    }
+
+    if normalized.as_os_str().is_empty() {
+        None
+    } else {
+        Some(normalized)
+    }
+}
+
+#[test]
+fn normalize_confines_to_root() {
+    let reject = AbsolutePaths::Reject;
+    assert_eq!(normalize(Path::new("pxelinux.0"), &reject), Some(PathBuf::from("pxelinux.0")));
+    assert_eq!(normalize(Path::new("./boot/../pxelinux.cfg/default"), &reject),
+               Some(PathBuf::from("pxelinux.cfg/default")));
+
+    assert_eq!(normalize(Path::new("../../etc/shadow"), &reject), None);
+    assert_eq!(normalize(Path::new("boot/../../etc/shadow"), &reject), None);
+    assert_eq!(normalize(Path::new("/etc/passwd"), &reject), None);
+    assert_eq!(normalize(Path::new(""), &reject), None);
+    assert_eq!(normalize(Path::new("boot/.."), &reject), None);
+
+    let map = AbsolutePaths::MapUnderRoot;
+    assert_eq!(normalize(Path::new("/etc/passwd"), &map), Some(PathBuf::from("etc/passwd")));
+    assert_eq!(normalize(Path::new("/../etc/passwd"), &map), None);
+}
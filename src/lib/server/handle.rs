@@ -0,0 +1,462 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, Condvar};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::config::BusyPolicy;
+use crate::sync::lock;
+use super::{Request, RequestKey};
+
+// State shared between a server, its handles and its worker threads
+pub(crate) struct ServerState {
+    shutting_down: AtomicBool,
+
+    // The transfers in progress and waiting to start, and a condition
+    // notified whenever a transfer finishes
+    transfers: Mutex<Transfers>,
+    finished: Condvar,
+
+    pub counters: Counters
+}
+
+struct Transfers {
+    active: usize,
+    queued: VecDeque<Request>,
+
+    // The requests being served or queued, so that a client retransmitting
+    // its request doesn't start a second transfer, and how many of them
+    // each client has
+    requests: HashSet<RequestKey>,
+    clients: HashMap<IpAddr, usize>
+}
+
+impl Transfers {
+    fn add(&mut self, key: RequestKey) {
+        *self.clients.entry(key.0.ip()).or_insert(0) += 1;
+        self.requests.insert(key);
+    }
+
+    fn remove(&mut self, key: &RequestKey) {
+        if !self.requests.remove(key) {
+            return;
+        }
+        let ip = key.0.ip();
+        let remaining = match self.clients.get_mut(&ip) {
+            Some(count) => {
+                *count -= 1;
+                *count
+            },
+            None => return
+        };
+        if remaining == 0 {
+            self.clients.remove(&ip);
+        }
+    }
+}
+
+// Counts of how the server has dealt with requests, kept so that abuse can
+// be noticed
+#[derive(Default)]
+pub(crate) struct Counters {
+    pub requests: AtomicU64,
+    pub duplicates: AtomicU64,
+    pub denied: AtomicU64,
+    pub busy: AtomicU64,
+    pub client_busy: AtomicU64,
+    pub flooding: AtomicU64,
+    pub bans: AtomicU64
+}
+
+impl Counters {
+    pub fn increment(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> ServerStats {
+        let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        ServerStats{
+            requests: get(&self.requests),
+            duplicate_requests: get(&self.duplicates),
+            denied_requests: get(&self.denied),
+            busy_rejections: get(&self.busy),
+            client_limit_rejections: get(&self.client_busy),
+            flood_drops: get(&self.flooding),
+            bans: get(&self.bans)
+        }
+    }
+}
+
+/// How many requests a server has received, and how many of them were
+/// turned away and why.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ServerStats {
+    /// Read and write requests received
+    pub requests: u64,
+
+    /// Requests ignored as retransmissions of one already being served
+    pub duplicate_requests: u64,
+
+    /// Requests refused or dropped by the access list
+    pub denied_requests: u64,
+
+    /// Requests refused because the server was running as many transfers as
+    /// it is allowed to
+    pub busy_rejections: u64,
+
+    /// Requests refused because their client was running as many transfers
+    /// as it is allowed to
+    pub client_limit_rejections: u64,
+
+    /// Requests dropped because their client was sending them too quickly,
+    /// including those dropped while it was banned for it
+    pub flood_drops: u64,
+
+    /// The number of times a client has been banned for flooding
+    pub bans: u64
+}
+
+// Whether a request can be served now
+pub(crate) enum Admission {
+    // Serve the request, which counts as a transfer in progress until the
+    // guard is dropped
+    Start(ActiveTransfer, Request),
+
+    // The request will be served by the next worker to finish
+    Queued,
+
+    // The server is too busy to serve the request
+    Busy,
+
+    // The client already has as many transfers as it may
+    ClientBusy,
+
+    // The request is a retransmission of one already being served or queued
+    Duplicate
+}
+
+impl ServerState {
+    pub fn new() -> ServerState {
+        ServerState{
+            shutting_down: AtomicBool::new(false),
+            transfers: Mutex::new(Transfers{
+                active: 0,
+                queued: VecDeque::new(),
+                requests: HashSet::new(),
+                clients: HashMap::new()
+            }),
+            finished: Condvar::new(),
+            counters: Counters::default()
+        }
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    // Decide whether `request` can start, given that at most `limit`
+    // transfers may be in progress at once, and that each client may have
+    // at most `client_limit` transfers in progress or queued
+    pub fn admit(state: &Arc<ServerState>, request: Request, limit: Option<usize>,
+                 client_limit: Option<usize>, policy: BusyPolicy) -> Admission {
+        let mut transfers = lock(&state.transfers);
+        if transfers.requests.contains(&request.key()) {
+            return Admission::Duplicate;
+        }
+
+        if let Some(client_limit) = client_limit {
+            let client = transfers.clients.get(&request.addr.ip()).cloned().unwrap_or(0);
+            if client >= client_limit {
+                return Admission::ClientBusy;
+            }
+        }
+
+        let at_capacity = match limit {
+            Some(limit) => transfers.active >= limit,
+            None => false
+        };
+
+        if !at_capacity {
+            transfers.active += 1;
+            transfers.add(request.key());
+            return Admission::Start(ActiveTransfer{
+                state: state.clone(),
+                serving: Some(request.key())
+            }, request);
+        }
+
+        match policy {
+            BusyPolicy::Queue(max) if transfers.queued.len() < max => {
+                transfers.add(request.key());
+                transfers.queued.push_back(request);
+                Admission::Queued
+            },
+            _ => Admission::Busy
+        }
+    }
+
+    // Take the queued requests that no worker is left to serve, so that
+    // they can be refused. This only happens when a worker could not be
+    // started, as otherwise the last worker to finish serves the queue.
+    pub fn take_stranded(&self) -> Vec<Request> {
+        let mut transfers = lock(&self.transfers);
+        if transfers.active > 0 {
+            return vec![];
+        }
+
+        let stranded: Vec<Request> = transfers.queued.drain(..).collect();
+        for request in &stranded {
+            transfers.remove(&request.key());
+        }
+        stranded
+    }
+}
+
+// Marks a transfer as in progress for as long as it is alive, including if
+// the worker running the transfer panics
+pub(crate) struct ActiveTransfer {
+    state: Arc<ServerState>,
+
+    // The request being served, or None once the transfer has finished
+    serving: Option<RequestKey>
+}
+
+impl ActiveTransfer {
+
+    // Take the next queued request, for the worker to serve once its current
+    // transfer is done. If there are none, or the server is shutting down,
+    // the transfer is finished instead. Both happen under the same lock as
+    // `admit`, so a request can't be queued just as the last worker exits.
+    pub fn next_request(&mut self) -> Option<Request> {
+        let mut guard = lock(&self.state.transfers);
+        let transfers = &mut *guard;
+        if let Some(ref key) = self.serving {
+            transfers.remove(key);
+        }
+        if self.state.is_shutting_down() {
+            let queued: Vec<Request> = transfers.queued.drain(..).collect();
+            for request in queued {
+                transfers.remove(&request.key());
+            }
+        }
+
+        let next = transfers.queued.pop_front();
+        self.serving = next.as_ref().map(|r| r.key());
+        if next.is_none() {
+            transfers.active -= 1;
+            self.state.finished.notify_all();
+        }
+        next
+    }
+}
+
+impl Drop for ActiveTransfer {
+    fn drop(&mut self) {
+        if let Some(ref key) = self.serving {
+            let mut transfers = lock(&self.state.transfers);
+            transfers.remove(key);
+            transfers.active -= 1;
+            self.state.finished.notify_all();
+        }
+    }
+}
+
+/// A handle used to stop a running `TftpServer` from another thread. It can
+/// be cloned freely.
+#[derive(Clone)]
+pub struct ServerHandle {
+    state: Arc<ServerState>
+}
+
+impl ServerHandle {
+    pub(crate) fn new(state: Arc<ServerState>) -> ServerHandle {
+        ServerHandle{
+            state: state
+        }
+    }
+
+    /// Stop the server from accepting new requests. `start` returns shortly
+    /// afterwards, while transfers already in progress carry on.
+    ///
+    /// If `timeout` is given, this waits up to that long for the transfers in
+    /// progress to finish. Returns whether there are no transfers left.
+    pub fn shutdown(&self, timeout: Option<Duration>) -> bool {
+        self.state.shutting_down.store(true, Ordering::SeqCst);
+
+        let timeout = match timeout {
+            Some(t) => t,
+            None => return self.active_transfers() == 0
+        };
+
+        let deadline = Instant::now() + timeout;
+        let mut transfers = lock(&self.state.transfers);
+        while transfers.active > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            transfers = match self.state.finished.wait_timeout(transfers, deadline - now) {
+                Ok((guard, _)) => guard,
+                Err(poisoned) => poisoned.into_inner().0
+            };
+        }
+        true
+    }
+
+    /// The number of transfers in progress.
+    pub fn active_transfers(&self) -> usize {
+        lock(&self.state.transfers).active
+    }
+
+    /// The number of requests waiting for a transfer to finish before they
+    /// can be served.
+    pub fn queued_requests(&self) -> usize {
+        lock(&self.state.transfers).queued.len()
+    }
+
+    /// Counts of the requests the server has received, and of those it has
+    /// turned away, since it was created.
+    pub fn stats(&self) -> ServerStats {
+        self.state.counters.snapshot()
+    }
+}
+
+#[cfg(test)]
+fn read_request(port: u16, filename: &str) -> Request {
+    use crate::codes::Opcode;
+
+    let mut packet = [0; 1024];
+    packet[1] = 1;
+    packet[2..2 + filename.len()].copy_from_slice(filename.as_bytes());
+    Request{
+        opcode: Opcode::ReadRequest,
+        addr: ([127, 0, 0, 1], port).into(),
+        local: ([127, 0, 0, 1], 0).into(),
+        packet: packet,
+        length: 2 + filename.len() + 1
+    }
+}
+
+#[test]
+fn admission_limits_and_queues_transfers() {
+    let state = Arc::new(ServerState::new());
+    let handle = ServerHandle::new(state.clone());
+    let admit = |request| ServerState::admit(&state, request, Some(1), None, BusyPolicy::Queue(1));
+
+    let mut first = match admit(read_request(1069, "a")) {
+        Admission::Start(active, _) => active,
+        _ => panic!("first request was not started")
+    };
+    assert!(match admit(read_request(1070, "a")) {
+        Admission::Queued => true,
+        _ => false
+    });
+    assert!(match admit(read_request(1071, "a")) {
+        Admission::Busy => true,
+        _ => false
+    });
+    assert_eq!((handle.active_transfers(), handle.queued_requests()), (1, 1));
+
+    // The worker picks up the queued request, then finishes
+    assert!(first.next_request().is_some());
+    assert_eq!((handle.active_transfers(), handle.queued_requests()), (1, 0));
+    assert!(first.next_request().is_none());
+    drop(first);
+    assert_eq!(handle.active_transfers(), 0);
+}
+
+#[test]
+fn admission_ignores_retransmitted_requests() {
+    use crate::codes::Opcode;
+
+    let state = Arc::new(ServerState::new());
+    let admit = |request| ServerState::admit(&state, request, None, None, BusyPolicy::Reject);
+    let is_duplicate = |admission| match admission {
+        Admission::Duplicate => true,
+        _ => false
+    };
+
+    let first = admit(read_request(1069, "pxelinux.0"));
+    assert!(is_duplicate(admit(read_request(1069, "pxelinux.0"))));
+    assert!(!is_duplicate(admit(read_request(1069, "pxelinux.cfg/default"))));
+    assert!(!is_duplicate(admit(read_request(1070, "pxelinux.0"))));
+
+    // Requests that differ in anything other than the filename aren't
+    // retransmissions either
+    let mut write = read_request(1069, "pxelinux.0");
+    write.opcode = Opcode::WriteRequest;
+    write.packet[1] = 2;
+    assert!(!is_duplicate(admit(write)));
+    let mut netascii = read_request(1069, "pxelinux.0");
+    netascii.packet[13..22].copy_from_slice(b"netascii\0");
+    netascii.length = 22;
+    assert!(!is_duplicate(admit(netascii)));
+
+    // Once the transfer is over, the same request starts a new one
+    drop(first);
+    assert!(!is_duplicate(admit(read_request(1069, "pxelinux.0"))));
+}
+
+#[cfg(test)]
+fn read_request_from(ip: [u8; 4], port: u16, filename: &str) -> Request {
+    let mut request = read_request(port, filename);
+    request.addr = (ip, port).into();
+    request
+}
+
+#[test]
+fn admission_limits_transfers_per_client() {
+    let state = Arc::new(ServerState::new());
+    let admit = |request| ServerState::admit(&state, request, Some(1), Some(1),
+                                             BusyPolicy::Queue(4));
+    let is_client_busy = |admission| match admission {
+        Admission::ClientBusy => true,
+        _ => false
+    };
+
+    let first = admit(read_request_from([127, 0, 0, 1], 1069, "a"));
+    assert!(is_client_busy(admit(read_request_from([127, 0, 0, 1], 1070, "b"))));
+
+    // Queued requests count towards the client's limit too
+    assert!(match admit(read_request_from([127, 0, 0, 2], 1069, "a")) {
+        Admission::Queued => true,
+        _ => false
+    });
+    assert!(is_client_busy(admit(read_request_from([127, 0, 0, 2], 1070, "b"))));
+
+    drop(first);
+    assert!(!is_client_busy(admit(read_request_from([127, 0, 0, 1], 1070, "b"))));
+}
+
+#[test]
+fn admission_releases_stranded_queue() {
+    let state = Arc::new(ServerState::new());
+    let handle = ServerHandle::new(state.clone());
+    let admit = |request| ServerState::admit(&state, request, Some(1), None, BusyPolicy::Queue(1));
+
+    let first = match admit(read_request(1069, "a")) {
+        Admission::Start(active, _) => active,
+        _ => panic!("first request was not started")
+    };
+    assert!(match admit(read_request(1070, "a")) {
+        Admission::Queued => true,
+        _ => false
+    });
+
+    // While a worker is running, it will serve the queue
+    assert!(state.take_stranded().is_empty());
+
+    // But if its thread could not be started, nothing will
+    drop(first);
+    assert_eq!((handle.active_transfers(), handle.queued_requests()), (0, 1));
+    let stranded = state.take_stranded();
+    assert_eq!(stranded.len(), 1);
+    assert_eq!(stranded[0].addr.port(), 1070);
+    assert_eq!(handle.queued_requests(), 0);
+
+    // The client can try again
+    assert!(match admit(read_request(1070, "a")) {
+        Admission::Start(..) => true,
+        _ => false
+    });
+}
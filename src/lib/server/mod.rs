@@ -1,23 +1,89 @@
 use std::net::{UdpSocket, SocketAddr, ToSocketAddrs};
 use std::ffi::OsStr;
-use std::path::{PathBuf, Path};
+use std::path::{Path, PathBuf};
 use std::thread;
+use std::panic;
+use std::any::Any;
 use std::str;
-use std::fs::File;
-use std::io::Error;
+use std::io;
+use std::ops::RangeInclusive;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use std::cmp;
 
-use codes::{ErrorCode, TransferMode, Opcode};
-use packet::{Packet, PacketBuff, get_packet_opcode};
-use packet::error::TftpError;
-use transfer::{recieve_file, send_file};
-use config::Config;
-use callback::Callback;
+use crate::codes::{ErrorCode, TransferMode, Opcode};
+use crate::packet::{Packet, PacketBuff, get_packet_opcode, parse_options};
+use crate::packet::error::TftpError;
+use crate::packet::data;
+use crate::transfer::{recieve_file, send_file};
+use crate::config::{Config, AbsolutePaths, BusyPolicy, RateLimits, RequestLimits, WritePolicy};
+use crate::options::TransferOptions;
+use crate::resolve::resolve_path;
+use crate::callback::{Callback, ReadHandler};
+use crate::storage::{Storage, DiskStorage, Source};
+use crate::remap::RemapRules;
+use crate::acl::{AccessList, Operation, Permission};
+use crate::ratelimit::RateLimiter;
+use crate::flood::{RequestTracker, Verdict};
+use crate::error::Error;
+use crate::socket::{TransferSocket, bind_in_range, block_on};
+use crate::pktinfo;
+
+mod handle;
+#[cfg(feature = "async")]
+mod nonblocking;
+
+pub use self::handle::{ServerHandle, ServerStats};
+use self::handle::{ServerState, Admission, ActiveTransfer, Counters};
+
+// How often (in ms) a running server checks whether it has been asked to
+// stop
+const SHUTDOWN_POLL_INTERVAL: u64 = 100;
+
+/// How a read request handler has chosen to answer a request
+pub enum ReadResponse {
+    /// Send the contents of the given stream as the requested file
+    Stream(Source),
+
+    /// Send the file at another path, relative to the root, instead
+    Redirect(PathBuf),
+
+    /// Refuse the request with the given error
+    Error(TftpError)
+}
+
+// A read or write request, as received by the server
+pub(crate) struct Request {
+    opcode: Opcode,
+    addr: SocketAddr,
+
+    // The address the client sent the request to, which the transfer must
+    // come from too, with port 0. This is unspecified if it isn't known.
+    local: SocketAddr,
+    packet: PacketBuff,
+    length: usize
+}
+
+// Identifies a request by the client that sent it and the request itself,
+// as sent, including its opcode, filename, mode and options.
+// Retransmissions of a request have the same key.
+pub(crate) type RequestKey = (SocketAddr, Vec<u8>);
+
+impl Request {
+    // The kind of request, for logging
+    fn kind(&self) -> &'static str {
+        if self.opcode == Opcode::ReadRequest { "read" } else { "write" }
+    }
+
+    fn key(&self) -> RequestKey {
+        (self.addr, self.packet[..self.length].to_vec())
+    }
+}
 
 pub struct TftpServer {
-    socket: UdpSocket,
-    config: Config
+    socket: Arc<UdpSocket>,
+    config: Config,
+    state: Arc<ServerState>
 }
 
 impl TftpServer {
@@ -32,63 +98,129 @@ impl TftpServer {
     /// Returns `Err` if an error occurs while binding to the given address
     pub fn new<A: ToSocketAddrs, S: AsRef<OsStr> + ?Sized>(addr: A, root: &S)
                                                            -> Result<TftpServer, Error> {
-        let socket = try!(UdpSocket::bind(addr));
+        let socket = UdpSocket::bind(addr)?;
         Ok(TftpServer {
-            socket: socket,
-            config: Config {
-                root: PathBuf::from(root),
-                file_read_started_callback:    None,
-                file_write_started_callback:   None,
-                file_read_completed_callback:  None,
-                file_write_completed_callback: None,
-
-                read_timeout: Some(Duration::from_millis(20)),
-                send_retry_attempts: 5
-            }
+            socket: Arc::new(socket),
+            config: Config::new(Arc::new(DiskStorage::new(root.as_ref()))),
+            state: Arc::new(ServerState::new())
         })
     }
 
-    /// Start the server. Requests will be handled in separate threads.
-    pub fn start(&self) -> ! {
-        loop {
+    /// Start the server. Requests will be handled in separate threads. This
+    /// runs until `shutdown` is called on one of the server's handles.
+    ///
+    /// # Failures
+    /// Returns `Err` if the server's socket cannot be configured
+    pub fn start(&self) -> Result<(), Error> {
+        self.serve_until(|| false)
+    }
+
+    /// Start the server, as with `start`, but also stop accepting requests
+    /// once `stop` returns true. `stop` is checked after each request and at
+    /// least every 100ms.
+    ///
+    /// Errors while receiving requests or serving a transfer are logged, and
+    /// do not stop the server.
+    ///
+    /// # Failures
+    /// Returns `Err` if the server's socket cannot be configured
+    pub fn serve_until<F: Fn() -> bool>(&self, stop: F) -> Result<(), Error> {
+        let poll_interval = Duration::from_millis(SHUTDOWN_POLL_INTERVAL);
+        self.socket.set_read_timeout(Some(poll_interval))?;
+        let listening = self.listen()?;
+
+        while !stop() && !self.state.is_shutting_down() {
             let mut packet_buffer = [0u8; 1024];
-            let (count, addr) = self.socket.recv_from(&mut packet_buffer).unwrap();
-            self.handle_request(addr, packet_buffer, count);
+            match pktinfo::recv_from(&self.socket, &mut packet_buffer) {
+                Ok((count, addr, local)) => {
+                    let local = local.unwrap_or(listening);
+                    self.handle_request(addr, local, packet_buffer, count)
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                              e.kind() == io::ErrorKind::TimedOut ||
+                              e.kind() == io::ErrorKind::Interrupted => (),
+
+                // Errors here are usually caused by a single bad packet, so
+                // keep serving, but don't spin if the error persists
+                Err(e) => {
+                    error!("Could not receive a request: {}", e);
+                    thread::sleep(poll_interval);
+                }
+            }
         }
+        Ok(())
+    }
+
+    // Prepare the server's socket to receive requests. Returns the address
+    // it is bound to, with port 0, which is where transfers come from unless
+    // the socket reports where each request was sent.
+    fn listen(&self) -> Result<SocketAddr, Error> {
+        let mut addr = self.socket.local_addr()?;
+        if addr.ip().is_unspecified() {
+            pktinfo::enable(&self.socket)?;
+        }
+        addr.set_port(0);
+        Ok(addr)
+    }
+
+    /// Get a handle that can be used to shut the server down from another
+    /// thread, and to wait for transfers in progress to finish.
+    pub fn handle(&self) -> ServerHandle {
+        ServerHandle::new(self.state.clone())
     }
 
     /// Set a callback function to be invoked when a request is made to read
-    /// a file. This callback will be passed the `File` being read, and its
-    /// `Path`.
-    pub fn on_read_started<F: Callback<Path, File> + 'static>(&mut self, callback: F) -> &mut Self {
+    /// a file. This callback will be passed the `Path` of the file being
+    /// read, relative to the root, and the address of the client.
+    pub fn on_read_started<F: Callback<Path, SocketAddr> + 'static>(&mut self, callback: F) -> &mut Self {
         self.config.file_read_started_callback = Some(Arc::new(callback));
         self
     }
 
     /// Set a callback function to be invoked when a request to read a file
-    /// has been fulfilled. This callback will be passed the `File` that was
-    /// read and its `Path`.
-    pub fn on_read_completed<F: Callback<Path, File> + 'static>(&mut self, callback: F) -> &mut Self {
+    /// has been fulfilled. This callback will be passed the `Path` of the
+    /// file that was read, relative to the root, and the address of the
+    /// client.
+    pub fn on_read_completed<F: Callback<Path, SocketAddr> + 'static>(&mut self, callback: F) -> &mut Self {
         self.config.file_read_completed_callback = Some(Arc::new(callback));
         self
     }
 
-    /// Set a callback function to be invoked when a request is made to read
-    /// a file. This callback will be passed the `File` being written and
-    /// its `Path`.
-    pub fn on_write_started<F: Callback<Path, File> + 'static>(&mut self, callback: F) -> &mut Self {
+    /// Set a callback function to be invoked when a request is made to write
+    /// a file. This callback will be passed the `Path` of the file being
+    /// written, relative to the root, and the address of the client.
+    pub fn on_write_started<F: Callback<Path, SocketAddr> + 'static>(&mut self, callback: F) -> &mut Self {
         self.config.file_write_started_callback = Some(Arc::new(callback));
         self
     }
 
     /// Set a callback function to be invoked when a request to write a file
-    /// has been fulfilled. This callback will be passed the `File` that was
-    /// written and its `Path`.
-    pub fn on_write_completed<F: Callback<Path, File> + 'static>(&mut self, callback: F) -> &mut Self {
+    /// has been fulfilled and the file has been committed to storage, so it
+    /// is visible to other clients. This callback will be passed the `Path`
+    /// of the file that was written, relative to the root, and the address
+    /// of the client.
+    pub fn on_write_completed<F: Callback<Path, SocketAddr> + 'static>(&mut self, callback: F) -> &mut Self {
         self.config.file_write_completed_callback = Some(Arc::new(callback));
         self
     }
 
+    /// Set a handler to be invoked for each request to read a file, before
+    /// the file is looked up in storage. The handler is passed the requested
+    /// `Path`, relative to the root, the address of the client and the
+    /// transfer mode, and may answer with generated content, redirect the
+    /// request to another file, or refuse it. If it returns None, the
+    /// request is served from storage as usual.
+    pub fn on_read_request<F: ReadHandler + 'static>(&mut self, handler: F) -> &mut Self {
+        self.config.read_request_handler = Some(Arc::new(handler));
+        self
+    }
+
+    /// Serve files from `storage` instead of from the root directory given
+    /// to `new`.
+    pub fn set_storage<S: Storage + 'static>(&mut self, storage: S) {
+        self.config.storage = Arc::new(storage);
+    }
+
     /// Sets the read timeout to the timeout specified.
     /// If the value specified is None, then read calls will block indefinitely.
     ///
@@ -103,33 +235,239 @@ impl TftpServer {
         self.config.send_retry_attempts = attempts;
     }
 
-    // Dispatch an incoming request to the appropriate handler. Does nothing
-    // if the packet is ill-formed or unexpected.
-    fn handle_request(&self, addr: SocketAddr, packet: PacketBuff, length: usize) {
-        if let Ok(code) = get_packet_opcode(length, &packet) {
-            match code {
-                Opcode::ReadRequest => self.handle_read_request(addr, packet, length),
-                Opcode::WriteRequest => self.handle_write_request(addr, packet, length),
-                _ => (),
+    /// Set the largest block size the server will agree to when a client
+    /// requests one with the `blksize` option. Values outside of the range
+    /// allowed by RFC 2348 (8 to 65464 bytes) are clamped to it.
+    pub fn set_max_block_size(&mut self, size: usize) {
+        self.config.max_block_size = cmp::max(data::MIN_BLOCK_SIZE,
+                                              cmp::min(size, data::MAX_BLOCK_SIZE));
+    }
+
+    /// Set the largest number of blocks a client may ask the server to send
+    /// before waiting for an ACK with the `windowsize` option. A value of 1
+    /// disables sliding windows. The default is 64.
+    pub fn set_max_window_size(&mut self, size: u16) {
+        self.config.max_window_size = cmp::max(size, 1);
+    }
+
+    /// Set the block number that follows block 65535 in transfers of more
+    /// than 65535 blocks, either 0 (the default) or 1. Clients may choose
+    /// for themselves with the `rollover` option.
+    pub fn set_block_rollover(&mut self, first: u16) {
+        self.config.block_rollover = cmp::min(first, 1);
+    }
+
+    /// Set the range of retransmission timeouts a client may request with the
+    /// `timeout` option. Requested timeouts outside of this range are not
+    /// acknowledged, and the default timeout is used instead. By default, any timeout allowed by RFC 2349 (1 to 255 seconds)
+    /// is accepted.
+    pub fn set_timeout_bounds(&mut self, min: Duration, max: Duration) {
+        self.config.min_timeout = min;
+        self.config.max_timeout = cmp::max(min, max);
+    }
+
+    /// Set the range of local ports that transfers are carried out from,
+    /// such as a range let through by a firewall. Each transfer takes a free
+    /// port from the range, and requests that arrive while every port is
+    /// taken are refused with an error. If the value specified is None (the
+    /// default), transfers use any free port chosen by the system.
+    pub fn set_port_range(&mut self, ports: Option<RangeInclusive<u16>>) {
+        self.config.port_range = ports;
+    }
+
+    /// Set how requests for absolute paths (such as `/pxelinux.0`) are
+    /// handled. By default they are refused, as are requests that use `..`
+    /// to leave the server root.
+    pub fn set_absolute_paths(&mut self, policy: AbsolutePaths) {
+        self.config.absolute_paths = policy;
+    }
+
+    /// Set rules to rewrite, accept or refuse requested filenames before
+    /// they are looked up under the root. Requests refused by the rules are
+    /// answered with an access violation.
+    pub fn set_remap_rules(&mut self, rules: RemapRules) {
+        self.config.remap_rules = Some(Arc::new(rules));
+    }
+
+    /// Set rules deciding which clients may read and write which files.
+    /// The rules are checked as each request arrives, before any other work
+    /// is done for it. By default, every client may make any request.
+    pub fn set_access_list(&mut self, list: AccessList) {
+        self.config.access_list = Some(Arc::new(list));
+    }
+
+    /// Set which write requests are accepted. By default, clients may write
+    /// new files anywhere under the root, but may not replace existing ones.
+    pub fn set_write_policy(&mut self, policy: WritePolicy) {
+        self.config.write_policy = policy;
+    }
+
+    /// Set the largest file (in bytes) a client may write. Write requests
+    /// that declare a larger size with the `tsize` option are refused up
+    /// front, and other uploads are aborted once they exceed it. If the value
+    /// specified is None, uploads are only limited by the free space under
+    /// the server root.
+    pub fn set_max_upload_size(&mut self, size: Option<u64>) {
+        self.config.max_upload_size = size;
+    }
+
+    /// Set the largest number of transfers that may be in progress at once.
+    /// What happens to requests that arrive while the server is at this
+    /// limit is set with `set_busy_policy`. If the value specified is None
+    /// (the default), there is no limit.
+    pub fn set_max_concurrent_transfers(&mut self, max: Option<usize>) {
+        self.config.max_concurrent_transfers = max;
+    }
+
+    /// Set what happens to requests that arrive while the maximum number of
+    /// transfers are in progress. By default, they are refused.
+    pub fn set_busy_policy(&mut self, policy: BusyPolicy) {
+        self.config.busy_policy = policy;
+    }
+
+    /// Set the largest number of transfers a single client IP address may
+    /// have in progress or queued at once. Requests beyond that are refused.
+    /// If the value specified is None (the default), there is no limit.
+    pub fn set_max_transfers_per_client(&mut self, max: Option<usize>) {
+        self.config.max_transfers_per_client = max;
+    }
+
+    /// Limit how quickly each client IP address may make requests, banning
+    /// clients that flood the server. Requests from banned clients are
+    /// dropped as they arrive, before any work is done for them. By default,
+    /// there is no limit.
+    pub fn set_request_limits(&mut self, limits: RequestLimits) {
+        self.config.request_tracker = Some(Arc::new(RequestTracker::new(limits)));
+    }
+
+    /// Limit the rate at which files are sent to clients, overall, to each
+    /// client address and for each transfer. Packets are paced individually,
+    /// so that windows of blocks are spread out rather than sent in bursts.
+    /// By default, there are no limits.
+    pub fn set_rate_limits(&mut self, limits: RateLimits) {
+        self.config.rate_limiter = Some(Arc::new(RateLimiter::new(limits)));
+    }
+
+    // Start a worker for an incoming request, if the server isn't too busy.
+    // Does nothing if the packet is ill-formed or unexpected.
+    fn handle_request(&self, addr: SocketAddr, local: SocketAddr, packet: PacketBuff, length: usize) {
+        if let Some((active, request)) = self.admit(addr, local, packet, length) {
+            self.spawn_worker(active, request);
+        }
+    }
+
+    // Decide whether a request from `addr` to `local` can be served now.
+    // Returns the request to serve if so, refusing it or queueing it
+    // otherwise.
+    fn admit(&self, addr: SocketAddr, local: SocketAddr, packet: PacketBuff,
+             length: usize) -> Option<(ActiveTransfer, Request)> {
+        let opcode = match get_packet_opcode(length, &packet) {
+            Ok(code @ Opcode::ReadRequest) | Ok(code @ Opcode::WriteRequest) => code,
+            _ => return None
+        };
+        let request = Request{
+            opcode: opcode,
+            addr: addr,
+            local: local,
+            packet: packet,
+            length: length
+        };
+        let kind = request.kind();
+        let counters = &self.state.counters;
+        Counters::increment(&counters.requests);
+
+        let verdict = match self.config.request_tracker {
+            Some(ref tracker) => tracker.check(addr.ip(), Instant::now()),
+            None => Verdict::Allow
+        };
+        match verdict {
+            Verdict::Allow => (),
+            Verdict::Ban => {
+                warn!("Banned {} for flooding the server with requests", addr.ip());
+                Counters::increment(&counters.bans);
+                Counters::increment(&counters.flooding);
+                return None;
+            },
+            Verdict::Banned => {
+                Counters::increment(&counters.flooding);
+                return None;
+            }
+        }
+
+        match self.check_access(&request) {
+            Permission::Allow => (),
+            Permission::Deny => {
+                Counters::increment(&counters.denied);
+                info!("Refused {} request from {}: denied by access list", kind, addr);
+                let _ = self.socket.send_to(&TftpError{
+                    code: ErrorCode::AccessViolation,
+                    message: None
+                }.as_packet(), addr);
+                return None;
+            },
+            Permission::Drop => {
+                Counters::increment(&counters.denied);
+                debug!("Dropped {} request from {}: denied by access list", kind, addr);
+                return None;
+            }
+        }
+
+        match ServerState::admit(&self.state, request, self.config.max_concurrent_transfers,
+                                 self.config.max_transfers_per_client, self.config.busy_policy) {
+            Admission::Start(active, request) => Some((active, request)),
+            Admission::Queued => None,
+
+            // The client will get its response from the transfer already
+            // started for the original request
+            Admission::Duplicate => {
+                Counters::increment(&counters.duplicates);
+                debug!("Ignored retransmitted {} request from {}", kind, addr);
+                None
+            },
+            Admission::Busy => {
+                self.refuse_busy(kind, addr);
+                None
+            },
+            Admission::ClientBusy => {
+                Counters::increment(&counters.client_busy);
+                info!("Refused {} request from {}: too many transfers from client", kind, addr);
+                let _ = self.socket.send_to(&TftpError{
+                    code: ErrorCode::Undefined,
+                    message: Some("Too many transfers in progress, try again later".to_string())
+                }.as_packet(), addr);
+                None
             }
         }
     }
 
-    // Extract the transfer mode and path from the given packet
-    fn parse_rw_request(packet: &PacketBuff, length: usize) -> Result<(&str, TransferMode), TftpError> {
+    // Refuse a request from `addr` because the server is too busy to serve it
+    fn refuse_busy(&self, kind: &str, addr: SocketAddr) {
+        Counters::increment(&self.state.counters.busy);
+        info!("Refused {} request from {}: server is busy", kind, addr);
+        // The client can retry, so if this fails, don't worry about it
+        let _ = self.socket.send_to(&TftpError{
+            code: ErrorCode::Undefined,
+            message: Some("Server is busy, try again later".to_string())
+        }.as_packet(), addr);
+    }
+
+    // Extract the path, transfer mode and requested options from the given
+    // packet
+    fn parse_rw_request(packet: &PacketBuff, length: usize)
+                        -> Result<(&str, TransferMode, Vec<(String, String)>), TftpError> {
         let packet = &packet[2..length];
         let mut parts = packet.splitn(3, |x| *x == 0);
 
-        let filename = match parts.next() {
-            Some(filename_buff) => str::from_utf8(filename_buff).unwrap(),
+        let filename = match parts.next().and_then(|b| str::from_utf8(b).ok()) {
+            Some(filename) => filename,
             None => return Err(TftpError{
                 code: ErrorCode::Undefined,
                 message: Some("Invalid filename".to_string())
             })
         };
 
-        let mode_str = match parts.next(){
-            Some(mode_str_buff) => str::from_utf8(mode_str_buff).unwrap(),
+        let mode_str = match parts.next().and_then(|b| str::from_utf8(b).ok()) {
+            Some(mode_str) => mode_str,
             None => return Err(TftpError{
                 code: ErrorCode::Undefined,
                 message: Some("Invalid mode string".to_string())
@@ -148,73 +486,567 @@ impl TftpServer {
                 message: Some("Unknown transfer mode".to_string())
             }),
         };
-        Ok((filename, mode))
+
+        // Anything after the mode is a list of option/value pairs (RFC 2347).
+        // If there is nothing at all, the mode wasn't terminated.
+        let options = match parts.next().and_then(parse_options) {
+            Some(o) => o,
+            None => return Err(TftpError{
+                code: ErrorCode::Undefined,
+                message: Some("Malformed options".to_string())
+            })
+        };
+        Ok((filename, mode, options))
     }
 
-    fn handle_write_request(&self, addr: SocketAddr, packet: PacketBuff, length: usize) {
-        let config = self.config.clone();
-        thread::spawn(move || {
-            let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
-            socket.set_read_timeout(config.read_timeout).unwrap();
+    // Find the path, relative to the root, of the file requested by `addr`,
+    // after applying any remap rules
+    fn resolve_request(config: &Config, filename: &str, addr: SocketAddr)
+                       -> Result<PathBuf, TftpError> {
+        let filename = match config.remap_rules {
+            Some(ref rules) => match rules.apply(filename, &addr.ip()) {
+                Some(f) => f,
+                None => return Err(TftpError{
+                    code: ErrorCode::AccessViolation,
+                    message: None
+                })
+            },
+            None => filename.to_string()
+        };
+        resolve_path(config, filename)
+    }
 
-            let (filename, mode) = match Self::parse_rw_request(&packet, length) {
-                Ok((f, m)) => (f, m),
-                Err(e) => {
-                    let _ = socket.send_to(&e.as_packet(), addr);
-                    return ();
+    // Ask the read request handler, if there is one, how to answer a request
+    // for `path`. Returns the path of the file to send, and the stream to
+    // send it from if it isn't to be read from storage.
+    fn choose_source(config: &Config, path: PathBuf, addr: SocketAddr, mode: TransferMode)
+                     -> Result<(PathBuf, Option<Source>), TftpError> {
+        let response = match config.read_request_handler {
+            Some(ref handler) => handler.handle(&path, &addr, mode),
+            None => None
+        };
+
+        match response {
+            None => Ok((path, None)),
+            Some(ReadResponse::Stream(source)) => Ok((path, Some(source))),
+            Some(ReadResponse::Redirect(target)) => {
+                // The access list only saw the requested path, so the one
+                // redirected to has to pass it too
+                let target = resolve_path(config, target)?;
+                let permission = match config.access_list {
+                    Some(ref list) => list.check(Operation::Read, &addr.ip(), Some(&target)),
+                    None => Permission::Allow
+                };
+                if permission != Permission::Allow {
+                    return Err(TftpError{
+                        code: ErrorCode::AccessViolation,
+                        message: None
+                    });
                 }
-            };
+                Ok((target, None))
+            },
+            Some(ReadResponse::Error(e)) => Err(e)
+        }
+    }
 
-            let full_path = config.root.join(filename);
+    // Check a request against the access list. Paths are matched after
+    // remapping and normalization, so that they can't be disguised.
+    fn check_access(&self, request: &Request) -> Permission {
+        let list = match self.config.access_list {
+            Some(ref list) => list,
+            None => return Permission::Allow
+        };
 
-            let file = match recieve_file(&config, &socket, &full_path, &mode, addr) {
-                Ok(f) => f,
+        let operation = if request.opcode == Opcode::ReadRequest {
+            Operation::Read
+        } else {
+            Operation::Write
+        };
+        let path = Self::parse_rw_request(&request.packet, request.length).ok()
+            .and_then(|(filename, _, _)| {
+                Self::resolve_request(&self.config, filename, request.addr).ok()
+            });
+        list.check(operation, &request.addr.ip(), path.as_ref().map(|p| p.as_path()))
+    }
 
-                // Sending the error is a courtesy, so if it fails, don't
-                // worry about it
-                Err(err) => {
-                    let _ = socket.send_to(&err.as_packet(), addr);
-                    return ();
+    // Serve `request` in its own thread, followed by any requests queued
+    // while it runs
+    fn spawn_worker(&self, active: ActiveTransfer, request: Request) {
+        let config = self.config.clone();
+        let listener = self.socket.clone();
+        let (kind, addr) = (request.kind(), request.addr);
+        let spawned = thread::Builder::new().spawn(move || {
+            // Counts as a transfer in progress until the thread exits
+            let mut active = active;
+            let mut request = request;
+            loop {
+                // A panic, such as in a callback, only ends this transfer,
+                // so that the requests queued behind it are still served
+                let serve = panic::AssertUnwindSafe(|| {
+                    match bind_transfer_socket(&config, &listener, &request) {
+                        Ok(socket) => block_on(Self::serve_request(&config, socket, &request)),
+                        Err(e) => Err(e)
+                    }
+                });
+                match panic::catch_unwind(serve) {
+                    Ok(result) => log_transfer(request.kind(), request.addr, result),
+                    Err(panic) => log_panic(request.kind(), request.addr, panic)
                 }
-            };
 
-            if let Some(callback) = config.file_write_completed_callback {
-                callback.call(&full_path, &file);
+                request = match active.next_request() {
+                    Some(r) => r,
+                    None => break
+                };
             }
         });
+
+        if let Err(e) = spawned {
+            error!("Could not start a thread for {} request from {}: {}", kind, addr, e);
+
+            // If no other worker is running, the requests queued behind this
+            // one would never be served
+            for request in self.state.take_stranded() {
+                self.refuse_busy(request.kind(), request.addr);
+            }
+        }
     }
 
+    // Answer a read or write request, carrying out the transfer over `socket`
+    async fn serve_request<S: TransferSocket>(config: &Config, socket: S,
+                                              request: &Request) -> Result<(), Error> {
+        let Request{ref opcode, addr, ref packet, length, ..} = *request;
+        if *opcode == Opcode::ReadRequest {
+            Self::serve_read_request(config, socket, addr, packet, length).await
+        } else {
+            Self::serve_write_request(config, socket, addr, packet, length).await
+        }
+    }
 
-    fn handle_read_request(&self, addr: SocketAddr, packet: PacketBuff, length: usize) {
-        let config = self.config.clone();
-        thread::spawn(move || {
-            let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
-            socket.set_read_timeout(config.read_timeout).unwrap();
+    // Answer a write request from `addr`, carrying out the transfer over
+    // `socket`
+    async fn serve_write_request<S: TransferSocket>(config: &Config, mut socket: S,
+                                                    addr: SocketAddr, packet: &PacketBuff,
+                                                    length: usize) -> Result<(), Error> {
+        let (filename, mode, requested) = match Self::parse_rw_request(packet, length) {
+            Ok(request) => request,
+            Err(e) => return Err(refuse(&socket, addr, e).await)
+        };
 
-            let (filename, mode) = match Self::parse_rw_request(&packet, length) {
-                Ok((f, m)) => (f, m),
-                Err(e) => {
-                    let _ = socket.send_to(&e.as_packet(), addr);
-                    return ();
-                }
-            };
+        let full_path = match Self::resolve_request(config, filename, addr) {
+            Ok(p) => p,
+            Err(e) => return Err(refuse(&socket, addr, e).await)
+        };
+        let options = TransferOptions::negotiate(config, &requested);
 
-            let full_path = config.root.join(filename);
+        // A timeout negotiated by the client only applies to this transfer
+        socket.set_timeout(options.timeout.or(config.read_timeout))?;
 
-            let file = match send_file(&config, &socket, &full_path, &mode, addr) {
-                Ok(f) => f,
+        if let Err(err) = recieve_file(config, &socket, &full_path, &mode, &options,
+                                       addr).await {
+            // Sending the error is a courtesy, so if it fails, don't
+            // worry about it
+            if let Some(e) = err.as_tftp_error() {
+                let _ = socket.send_to(&e.as_packet(), addr).await;
+            }
+            return Err(Error::from(err));
+        }
 
-                // Sending the error is a courtesy, so if it fails, don't
-                // worry about it
-                Err(err) => {
-                    let _ = socket.send_to(&err.as_packet(), addr);
-                    return ();
-                }
-            };
+        if let Some(ref callback) = config.file_write_completed_callback {
+            callback.call(&full_path, &addr);
+        }
+        Ok(())
+    }
+
+    // Answer a read request from `addr`, carrying out the transfer over
+    // `socket`
+    async fn serve_read_request<S: TransferSocket>(config: &Config, mut socket: S,
+                                                   addr: SocketAddr, packet: &PacketBuff,
+                                                   length: usize) -> Result<(), Error> {
+        let (filename, mode, requested) = match Self::parse_rw_request(packet, length) {
+            Ok(request) => request,
+            Err(e) => return Err(refuse(&socket, addr, e).await)
+        };
+
+        let full_path = match Self::resolve_request(config, filename, addr) {
+            Ok(p) => p,
+            Err(e) => return Err(refuse(&socket, addr, e).await)
+        };
+        let (full_path, source) = match Self::choose_source(config, full_path, addr, mode) {
+            Ok(chosen) => chosen,
+            Err(e) => return Err(refuse(&socket, addr, e).await)
+        };
+        let options = TransferOptions::negotiate(config, &requested);
+
+        // A timeout negotiated by the client only applies to this transfer
+        socket.set_timeout(options.timeout.or(config.read_timeout))?;
 
-            if let Some(callback) = config.file_read_completed_callback {
-                callback.call(&full_path, &file);
+        if let Err(err) = send_file(config, &socket, &full_path, source, &mode,
+                                    &options, addr).await {
+            // Sending the error is a courtesy, so if it fails, don't
+            // worry about it
+            if let Some(e) = err.as_tftp_error() {
+                let _ = socket.send_to(&e.as_packet(), addr).await;
             }
-        });
+            return Err(Error::from(err));
+        }
+
+        if let Some(ref callback) = config.file_read_completed_callback {
+            callback.call(&full_path, &addr);
+        }
+        Ok(())
+    }
+}
+
+// Refuse a request by sending `error` to the client. As with other errors,
+// sending it is a courtesy, so failing to is ignored.
+async fn refuse<S: TransferSocket>(socket: &S, addr: SocketAddr, error: TftpError) -> Error {
+    let _ = socket.send_to(&error.as_packet(), addr).await;
+    Error::Local(error)
+}
+
+// Bind the socket to carry out the transfer for `request` over. If none can
+// be bound, the client is told so from `listener`, the socket the request
+// arrived on, as there is no other socket to send from. Only running out of
+// ports is worth retrying, so other failures get a generic error, and are
+// logged by the caller.
+fn bind_transfer_socket(config: &Config, listener: &UdpSocket,
+                        request: &Request) -> Result<UdpSocket, Error> {
+    match bind_in_range(request.local, config.port_range.as_ref()) {
+        Ok(socket) => Ok(socket),
+        Err(e) => {
+            let message = if e.kind() == io::ErrorKind::AddrInUse {
+                Some("No free ports for the transfer, try again later".to_string())
+            } else {
+                None
+            };
+            let _ = listener.send_to(&TftpError{
+                code: ErrorCode::Undefined,
+                message: message
+            }.as_packet(), request.addr);
+            Err(Error::from(e))
+        }
+    }
+}
+
+// Log that serving a transfer panicked, with the panic's message if it has
+// one
+fn log_panic(kind: &str, addr: SocketAddr, panic: Box<dyn Any + Send>) {
+    let message = match panic.downcast_ref::<&str>() {
+        Some(m) => m.to_string(),
+        None => match panic.downcast_ref::<String>() {
+            Some(m) => m.clone(),
+            None => "unknown cause".to_string()
+        }
+    };
+    error!("Panicked while serving {} request from {}: {}", kind, addr, message);
+}
+
+// Log the reason a transfer failed, if it did
+fn log_transfer(kind: &str, addr: SocketAddr, result: Result<(), Error>) {
+    match result {
+        Ok(()) => (),
+
+        // Refusing a request is part of normal operation
+        Err(Error::Local(ref e)) => {
+            info!("Refused {} request from {}: {:?}", kind, addr, e.code)
+        },
+        Err(Error::Io(ref e)) => {
+            error!("Failed to serve {} request from {}: {}", kind, addr, e)
+        },
+        Err(e) => warn!("Failed to serve {} request from {}: {}", kind, addr, e)
     }
 }
+
+// A server on a free loopback port that keeps files in `storage`, and the
+// address to send it requests at
+#[cfg(test)]
+pub(crate) fn test_server<S: Storage + 'static>(storage: S) -> (TftpServer, SocketAddr) {
+    let mut server = TftpServer::new("127.0.0.1:0", "").unwrap();
+    server.set_storage(storage);
+
+    // Tests run in parallel, so allow more than the default for responses
+    server.set_read_timeout(Some(Duration::from_millis(200)));
+    let addr = server.socket.local_addr().unwrap();
+    (server, addr)
+}
+
+// Serve requests with `server` in a new thread, until the returned handle is
+// used to shut it down
+#[cfg(test)]
+pub(crate) fn run_test_server(server: TftpServer) -> ServerHandle {
+    let handle = server.handle();
+    thread::spawn(move || server.start().unwrap());
+    handle
+}
+
+#[test]
+fn read_handler_chooses_source() {
+    use std::io::Cursor;
+    use std::sync::Mutex;
+    use crate::client::TftpClient;
+    use crate::storage::MemoryStorage;
+
+    let storage = MemoryStorage::new();
+    storage.insert("boot/default.cfg", "default local\n");
+    storage.insert("boot.img", "from storage");
+    let (mut server, addr) = test_server(storage);
+    server.on_read_request(|path: &Path, addr: &SocketAddr, _| {
+        let generated = format!("client {}\n", addr.ip());
+        match path.to_str() {
+            Some("pxelinux.cfg/01-aa") => Some(ReadResponse::Stream(Source{
+                size: Some(generated.len() as u64),
+                reader: Box::new(Cursor::new(generated))
+            })),
+            Some("unsized.cfg") => Some(ReadResponse::Stream(Source{
+                size: None,
+                reader: Box::new(Cursor::new(generated))
+            })),
+            Some("pxelinux.cfg/default") => {
+                Some(ReadResponse::Redirect(PathBuf::from("boot/default.cfg")))
+            },
+            Some("secret") => Some(ReadResponse::Error(TftpError{
+                code: ErrorCode::AccessViolation,
+                message: Some("Not for you".to_string())
+            })),
+            _ => None
+        }
+    });
+    let handle = run_test_server(server);
+
+    let size = Arc::new(Mutex::new(None));
+    let mut client = TftpClient::new(addr).unwrap();
+    let reported = size.clone();
+    client.on_progress(move |_: &u64, total: &Option<u64>| {
+        *reported.lock().unwrap() = *total;
+    });
+    let get = |name| {
+        let mut received = vec![];
+        client.get_into(name, &mut received).map(|_| received)
+    };
+
+    // A stream's size is sent with `tsize`, if it has one
+    assert_eq!(get("pxelinux.cfg/01-aa").unwrap(), b"client 127.0.0.1\n".to_vec());
+    assert_eq!(*size.lock().unwrap(), Some(17));
+    assert_eq!(get("unsized.cfg").unwrap(), b"client 127.0.0.1\n".to_vec());
+    assert_eq!(*size.lock().unwrap(), None);
+
+    assert_eq!(get("pxelinux.cfg/default").unwrap(), b"default local\n".to_vec());
+    match get("secret") {
+        Err(Error::Remote(ref e)) => {
+            assert_eq!(e.code, ErrorCode::AccessViolation);
+            assert_eq!(e.message, Some("Not for you".to_string()));
+        },
+        r => panic!("request was not refused: {:?}", r)
+    }
+
+    // Requests the handler leaves alone are served from storage
+    assert_eq!(get("boot.img").unwrap(), b"from storage".to_vec());
+    assert!(handle.shutdown(Some(Duration::from_secs(5))));
+}
+
+#[test]
+fn redirect_is_checked_against_access_list() {
+    use crate::client::TftpClient;
+    use crate::storage::MemoryStorage;
+
+    let storage = MemoryStorage::new();
+    storage.insert("private/keys", "secret");
+    storage.insert("public/motd", "hello");
+    let (mut server, addr) = test_server(storage);
+    server.set_access_list(AccessList::parse("deny read any ^private/").unwrap());
+    server.on_read_request(|path: &Path, _: &SocketAddr, _| {
+        match path.to_str() {
+            Some("keys") => Some(ReadResponse::Redirect(PathBuf::from("private/keys"))),
+            Some("motd") => Some(ReadResponse::Redirect(PathBuf::from("public/motd"))),
+            _ => None
+        }
+    });
+    let handle = run_test_server(server);
+
+    let mut client = TftpClient::new(addr).unwrap();
+    let mut received = vec![];
+    match client.get_into("keys", &mut received) {
+        Err(Error::Remote(ref e)) => assert_eq!(e.code, ErrorCode::AccessViolation),
+        r => panic!("redirect was not refused: {:?}", r)
+    }
+    assert!(received.is_empty());
+
+    client.get_into("motd", &mut received).unwrap();
+    assert_eq!(received, b"hello".to_vec());
+    assert!(handle.shutdown(Some(Duration::from_secs(5))));
+}
+
+// A stream that panics when it is read, after keeping the transfer busy for
+// a while
+#[cfg(test)]
+pub(crate) struct PanickingReader;
+
+#[cfg(test)]
+impl io::Read for PanickingReader {
+    fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+        thread::sleep(Duration::from_millis(200));
+        panic!("stream broke");
+    }
+}
+
+// Set up `server` to serve one transfer at a time, queueing others, with
+// requests for "panic" served from a `PanickingReader`
+#[cfg(test)]
+pub(crate) fn serve_panicking_reads(server: &mut TftpServer) {
+    server.set_max_concurrent_transfers(Some(1));
+    server.set_busy_policy(BusyPolicy::Queue(4));
+    server.on_read_request(|path: &Path, _: &SocketAddr, _| {
+        if path == Path::new("panic") {
+            Some(ReadResponse::Stream(Source{
+                reader: Box::new(PanickingReader),
+                size: None
+            }))
+        } else {
+            None
+        }
+    });
+}
+
+// Request "panic" from `server` without waiting for an answer
+#[cfg(test)]
+pub(crate) fn request_panic(server: SocketAddr) {
+    use crate::packet::request::TftpRequest;
+
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.send_to(&TftpRequest{
+        opcode: Opcode::ReadRequest,
+        filename: "panic".to_string(),
+        mode: TransferMode::Octet,
+        options: vec![]
+    }.as_packet(), server).unwrap();
+}
+
+#[test]
+fn worker_serves_queue_after_panic() {
+    use crate::client::TftpClient;
+    use crate::storage::MemoryStorage;
+
+    let storage = MemoryStorage::new();
+    storage.insert("boot.img", "queued");
+    let (mut server, addr) = test_server(storage);
+    serve_panicking_reads(&mut server);
+    let handle = run_test_server(server);
+
+    // The request for "boot.img" waits behind the one that panics
+    request_panic(addr);
+    let mut received = vec![];
+    TftpClient::new(addr).unwrap().get_into("boot.img", &mut received).unwrap();
+    assert_eq!(received, b"queued".to_vec());
+    assert_eq!(handle.stats().busy_rejections, 0);
+    assert!(handle.shutdown(Some(Duration::from_secs(5))));
+}
+
+// A stream that takes a while to read, so that transfers from it stay in
+// progress for a while
+#[cfg(test)]
+struct SlowReader(io::Cursor<Vec<u8>>);
+
+#[cfg(test)]
+impl io::Read for SlowReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        thread::sleep(Duration::from_millis(100));
+        self.0.read(buf)
+    }
+}
+
+#[test]
+fn shutdown_waits_for_transfers_until_deadline() {
+    use crate::client::TftpClient;
+    use crate::storage::MemoryStorage;
+
+    let (mut server, addr) = test_server(MemoryStorage::new());
+    server.on_read_request(|_: &Path, _: &SocketAddr, _| {
+        Some(ReadResponse::Stream(Source{
+            reader: Box::new(SlowReader(io::Cursor::new(vec![1; 1000]))),
+            size: None
+        }))
+    });
+    let handle = run_test_server(server);
+
+    let download = thread::spawn(move || {
+        let mut received = vec![];
+        TftpClient::new(addr).unwrap().get_into("slow.img", &mut received).map(|_| received)
+    });
+    while handle.active_transfers() == 0 {
+        thread::sleep(Duration::from_millis(5));
+    }
+
+    // The transfer in progress carries on after the server stops, for as
+    // long as it takes
+    assert!(!handle.shutdown(None));
+    assert!(!handle.shutdown(Some(Duration::from_millis(20))));
+    assert!(handle.shutdown(Some(Duration::from_secs(5))));
+    assert_eq!(download.join().unwrap().unwrap(), vec![1; 1000]);
+}
+
+#[test]
+fn serve_until_returns_when_stopped() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use crate::client::TftpClient;
+    use crate::storage::MemoryStorage;
+
+    let storage = MemoryStorage::new();
+    storage.insert("boot.img", "image");
+    let (server, addr) = test_server(storage);
+    let stop = Arc::new(AtomicBool::new(false));
+    let stopping = stop.clone();
+    let serving = thread::spawn(move || {
+        let result = server.serve_until(|| stopping.load(Ordering::SeqCst));
+        (server, result)
+    });
+
+    let mut client = TftpClient::new(addr).unwrap();
+    client.set_send_retry_attempts(0);
+    assert!(client.get_into("boot.img", &mut vec![]).is_ok());
+
+    // Once stopped, requests are no longer answered, though the socket is
+    // still open while the server is
+    stop.store(true, Ordering::SeqCst);
+    let (_server, result) = serving.join().unwrap();
+    assert!(result.is_ok());
+    match client.get_into("boot.img", &mut vec![]) {
+        Err(Error::TimedOut) => (),
+        r => panic!("request was answered after stopping: {:?}", r)
+    }
+}
+
+#[test]
+fn malformed_requests_are_refused() {
+    use crate::client::TftpClient;
+    use crate::storage::MemoryStorage;
+
+    let storage = MemoryStorage::new();
+    storage.insert("a.bin", "still serving");
+    let (server, addr) = test_server(storage);
+    let handle = run_test_server(server);
+
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+    let mut buffer = [0u8; 512];
+    let refused: &[&[u8]] = &[
+        b"\x00\x01\xff\xfe.bin\x00octet\x00",   // Filename isn't UTF-8
+        b"\x00\x01a.bin",                          // Filename isn't terminated
+        b"\x00\x01a.bin\x00octet",                 // Mode isn't terminated
+        b"\x00\x01a.bin\x00\xffctet\x00",         // Mode isn't UTF-8
+        b"\x00\x01a.bin\x00octet\x00blksize\x00"  // Option has no value
+    ];
+    for packet in refused {
+        socket.send_to(packet, addr).unwrap();
+        let (count, _) = socket.recv_from(&mut buffer).unwrap();
+        assert!(TftpError::from_buffer(&buffer[..count]).is_some());
+    }
+
+    // Packets too short to be requests at all are ignored
+    socket.send_to(b"\x00", addr).unwrap();
+    assert!(socket.recv_from(&mut buffer).is_err());
+
+    let mut received = vec![];
+    TftpClient::new(addr).unwrap().get_into("a.bin", &mut received).unwrap();
+    assert_eq!(received, b"still serving".to_vec());
+    assert!(handle.shutdown(Some(Duration::from_secs(5))));
+}
@@ -0,0 +1,186 @@
+use std::future::{self, Future};
+use std::io;
+use std::pin::pin;
+use std::time::Duration;
+
+use tokio::io::Interest;
+use tokio::net::UdpSocket;
+
+use crate::error::Error;
+use crate::socket::AsyncSocket;
+use crate::pktinfo;
+
+use super::{TftpServer, Request, SHUTDOWN_POLL_INTERVAL, bind_transfer_socket, log_transfer,
+            log_panic};
+use super::handle::ActiveTransfer;
+
+impl TftpServer {
+
+    /// Start the server on the current tokio runtime. This behaves as
+    /// `start` does, with the same configuration and callbacks, except that
+    /// each transfer runs as a task instead of in its own thread.
+    ///
+    /// Files are opened, read and written on tokio's blocking threads, but
+    /// callbacks and read request handlers are still called from these
+    /// tasks, so should not block for long. `ServerHandle::shutdown` blocks
+    /// while it waits for transfers, so should be called from outside the
+    /// runtime, or with `tokio::task::spawn_blocking`.
+    ///
+    /// # Failures
+    /// Returns `Err` if the server's socket cannot be registered with the
+    /// runtime
+    pub async fn serve_async(&self) -> Result<(), Error> {
+        self.serve_async_until(future::pending()).await
+    }
+
+    /// Start the server on the current tokio runtime, as with
+    /// `serve_async`, but also stop accepting requests once `stop`
+    /// completes.
+    ///
+    /// # Failures
+    /// Returns `Err` if the server's socket cannot be registered with the
+    /// runtime
+    pub async fn serve_async_until<F: Future<Output = ()>>(&self, stop: F) -> Result<(), Error> {
+        let listening = self.listen()?;
+
+        // Packets are read from a clone of the socket, so that the address
+        // each was sent to can be read too, once tokio says one is ready
+        let receiver = self.socket.try_clone()?;
+        receiver.set_nonblocking(true)?;
+        let listener = UdpSocket::from_std(receiver.try_clone()?)?;
+
+        let poll_interval = Duration::from_millis(SHUTDOWN_POLL_INTERVAL);
+        let mut stop = pin!(stop);
+        while !self.state.is_shutting_down() {
+            let mut packet_buffer = [0u8; 1024];
+            let receive = listener.async_io(Interest::READABLE, || {
+                pktinfo::recv_from(&receiver, &mut packet_buffer)
+            });
+            let received = tokio::select! {
+                _ = &mut stop => break,
+                r = tokio::time::timeout(poll_interval, receive) => r
+            };
+
+            match received {
+                Ok(Ok((count, addr, local))) => {
+                    let local = local.unwrap_or(listening);
+                    if let Some((active, request)) = self.admit(addr, local, packet_buffer, count) {
+                        self.spawn_async_worker(active, request);
+                    }
+                },
+
+                // Nothing arrived before it was time to check for shutdown
+                Err(_) => (),
+                Ok(Err(ref e)) if e.kind() == io::ErrorKind::Interrupted => (),
+                Ok(Err(e)) => {
+                    error!("Could not receive a request: {}", e);
+                    tokio::time::sleep(poll_interval).await;
+                }
+            }
+        }
+
+        // The socket is shared with `start`, which expects it to block
+        self.socket.set_nonblocking(false)?;
+        Ok(())
+    }
+
+    // Serve `request` in a new task, followed by any requests queued while
+    // it runs
+    fn spawn_async_worker(&self, active: ActiveTransfer, request: Request) {
+        let config = self.config.clone();
+        let listener = self.socket.clone();
+        tokio::spawn(async move {
+            // Counts as a transfer in progress until the task finishes
+            let mut active = active;
+            let mut request = request;
+            loop {
+                // The transfer runs as a task of its own, so that if it
+                // panics, such as in a callback, the requests queued behind
+                // it are still served
+                let (kind, addr) = (request.kind(), request.addr);
+                let (config, listener) = (config.clone(), listener.clone());
+                let transfer = tokio::spawn(async move {
+                    let socket = bind_transfer_socket(&config, &listener, &request)
+                        .and_then(|s| Ok(AsyncSocket::from_std(s)?));
+                    match socket {
+                        Ok(socket) => Self::serve_request(&config, socket, &request).await,
+                        Err(e) => Err(e)
+                    }
+                });
+                match transfer.await {
+                    Ok(result) => log_transfer(kind, addr, result),
+                    Err(e) => match e.try_into_panic() {
+                        Ok(panic) => log_panic(kind, addr, panic),
+                        Err(e) => error!("Failed to serve {} request from {}: {}", kind, addr, e)
+                    }
+                }
+
+                request = match active.next_request() {
+                    Some(r) => r,
+                    None => break
+                };
+            }
+        });
+    }
+}
+
+#[tokio::test]
+async fn serve_async_loopback() {
+    use crate::client::TftpClient;
+    use crate::storage::MemoryStorage;
+    use super::test_server;
+
+    // Several blocks, the last of them partly full
+    let data: Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+    let storage = MemoryStorage::new();
+    storage.insert("boot.img", data.clone());
+    let (server, addr) = test_server(storage.clone());
+    let handle = server.handle();
+    let client = TftpClient::new(addr).unwrap();
+
+    let transfers = async {
+        let mut received = vec![];
+        assert_eq!(client.get_into_async("boot.img", &mut received).await.unwrap(), 3000);
+        assert_eq!(received, data);
+
+        assert_eq!(client.put_from_async(&mut &data[..], Some(3000), "upload.img").await.unwrap(),
+                   3000);
+
+        // The upload is committed after the last block is acknowledged, so
+        // wait for the transfer to finish
+        let finished = tokio::task::spawn_blocking(move || {
+            handle.shutdown(Some(Duration::from_secs(5)))
+        });
+        assert!(finished.await.unwrap());
+    };
+    server.serve_async_until(transfers).await.unwrap();
+    assert_eq!(storage.get("upload.img"), Some(data));
+}
+
+#[tokio::test]
+async fn async_worker_serves_queue_after_panic() {
+    use crate::client::TftpClient;
+    use crate::storage::MemoryStorage;
+    use super::{test_server, serve_panicking_reads, request_panic};
+
+    let storage = MemoryStorage::new();
+    storage.insert("boot.img", "queued");
+    let (mut server, addr) = test_server(storage);
+    serve_panicking_reads(&mut server);
+    let handle = server.handle();
+    let client = TftpClient::new(addr).unwrap();
+
+    // The request for "boot.img" waits behind the one that panics
+    let transfers = async {
+        request_panic(addr);
+        let mut received = vec![];
+        client.get_into_async("boot.img", &mut received).await.unwrap();
+        assert_eq!(received, b"queued".to_vec());
+
+        let finished = tokio::task::spawn_blocking(move || {
+            handle.shutdown(Some(Duration::from_secs(5)))
+        });
+        assert!(finished.await.unwrap());
+    };
+    server.serve_async_until(transfers).await.unwrap();
+}
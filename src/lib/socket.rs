@@ -0,0 +1,239 @@
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::ops::RangeInclusive;
+use std::pin::pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+use std::time::Duration;
+
+/// A socket that a transfer is carried out over. Transfers are written once,
+/// as async functions, and run either on a blocking `UdpSocket` with
+/// `block_on`, or on a tokio socket with the `async` feature.
+pub trait TransferSocket {
+    /// Send `packet` to `addr`
+    async fn send_to(&self, packet: &[u8], addr: SocketAddr) -> io::Result<usize>;
+
+    /// Receive a packet, failing if none arrives within the socket's timeout
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+
+    /// Wait for `duration` before carrying on, as a rate limited transfer
+    /// does between packets
+    async fn pause(&self, duration: Duration);
+
+    /// Set how long `recv_from` waits for a packet. If the value specified
+    /// is None, it waits indefinitely.
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// Run `f`, which may block for a while (such as by reading a file),
+    /// without holding up other transfers. `f` is boxed because a closure
+    /// that borrows nothing can still have lifetimes in its type (such as
+    /// that of an `Arc<dyn Storage>`), and a transfer holding one across
+    /// an await can't be proven `Send`.
+    async fn run_blocking<T: Send + 'static>(&self, f: Box<dyn FnOnce() -> T + Send>) -> T;
+}
+
+// The blocking socket's operations complete before returning, so they are
+// always ready the first time they are polled
+impl TransferSocket for UdpSocket {
+    async fn send_to(&self, packet: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, packet, addr)
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buf)
+    }
+
+    async fn pause(&self, duration: Duration) {
+        thread::sleep(duration)
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.set_read_timeout(timeout)
+    }
+
+    // Each transfer has a thread of its own, so may as well block it
+    async fn run_blocking<T: Send + 'static>(&self, f: Box<dyn FnOnce() -> T + Send>) -> T {
+        f()
+    }
+}
+
+/// Bind a blocking socket for a transfer to the address `local`, on a port
+/// from `ports` if given, or on any free port otherwise. Ports in the range
+/// are tried in turn, starting from a random one, until one is free. The
+/// port of `local` itself is ignored.
+///
+/// # Failures
+/// Returns an `AddrInUse` error if every port in the range is taken
+pub fn bind_in_range(local: SocketAddr, ports: Option<&RangeInclusive<u16>>) -> io::Result<UdpSocket> {
+    let mut addr = local;
+    let ports = match ports {
+        Some(ports) => ports,
+        None => {
+            addr.set_port(0);
+            return UdpSocket::bind(addr);
+        }
+    };
+
+    let (first, last) = (*ports.start() as u64, *ports.end() as u64);
+    let count = (last + 1).saturating_sub(first);
+    let offset = if count > 0 {
+        RandomState::new().build_hasher().finish() % count
+    } else {
+        0
+    };
+
+    for i in 0..count {
+        addr.set_port((first + (offset + i) % count) as u16);
+        match UdpSocket::bind(addr) {
+            Ok(socket) => return Ok(socket),
+            Err(ref e) if e.kind() == io::ErrorKind::AddrInUse => (),
+            Err(e) => return Err(e)
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::AddrInUse, "No free port in the transfer port range"))
+}
+
+/// Run a transfer over a blocking `UdpSocket` to completion on the current
+/// thread. The socket blocks instead of waiting, so a transfer is normally
+/// ready the first time it is polled, but anything else it waits on is
+/// waited for by parking the thread until it is woken.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut context = Context::from_waker(&waker);
+    loop {
+        match future.as_mut().poll(&mut context) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park()
+        }
+    }
+}
+
+// Wakes a thread parked in `block_on`
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// A tokio socket, with a timeout for receiving packets
+#[cfg(feature = "async")]
+pub struct AsyncSocket {
+    socket: tokio::net::UdpSocket,
+    timeout: Option<Duration>
+}
+
+#[cfg(feature = "async")]
+impl AsyncSocket {
+
+    /// Bind a socket on the current tokio runtime
+    pub async fn bind(addr: SocketAddr) -> io::Result<AsyncSocket> {
+        Ok(AsyncSocket {
+            socket: tokio::net::UdpSocket::bind(addr).await?,
+            timeout: None
+        })
+    }
+
+    /// Register a blocking socket with the current tokio runtime
+    pub fn from_std(socket: UdpSocket) -> io::Result<AsyncSocket> {
+        socket.set_nonblocking(true)?;
+        Ok(AsyncSocket {
+            socket: tokio::net::UdpSocket::from_std(socket)?,
+            timeout: None
+        })
+    }
+}
+
+#[cfg(feature = "async")]
+impl TransferSocket for AsyncSocket {
+    async fn send_to(&self, packet: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        self.socket.send_to(packet, addr).await
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        match self.timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, self.socket.recv_from(buf)).await {
+                Ok(result) => result,
+                Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "Timed out waiting for a packet"))
+            },
+            None => self.socket.recv_from(buf).await
+        }
+    }
+
+    async fn pause(&self, duration: Duration) {
+        tokio::time::sleep(duration).await
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    async fn run_blocking<T: Send + 'static>(&self, f: Box<dyn FnOnce() -> T + Send>) -> T {
+        match tokio::task::spawn_blocking(f).await {
+            Ok(output) => output,
+            Err(e) => match e.try_into_panic() {
+                Ok(panic) => std::panic::resume_unwind(panic),
+                Err(e) => panic!("Blocking task did not finish: {}", e)
+            }
+        }
+    }
+}
+
+#[test]
+fn block_on_waits_until_woken() {
+    use std::sync::Mutex;
+
+    // Pending until another thread has set the value and woken it
+    struct Shared(Arc<Mutex<(Option<u32>, Option<Waker>)>>);
+
+    impl Future for Shared {
+        type Output = u32;
+
+        fn poll(self: std::pin::Pin<&mut Self>, context: &mut Context) -> Poll<u32> {
+            let mut shared = self.0.lock().unwrap();
+            match shared.0 {
+                Some(value) => Poll::Ready(value),
+                None => {
+                    shared.1 = Some(context.waker().clone());
+                    Poll::Pending
+                }
+            }
+        }
+    }
+
+    let shared = Arc::new(Mutex::new((None, None::<Waker>)));
+    let setter = shared.clone();
+    let thread = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        let mut shared = setter.lock().unwrap();
+        shared.0 = Some(7);
+        if let Some(waker) = shared.1.take() {
+            waker.wake();
+        }
+    });
+    assert_eq!(block_on(Shared(shared)), 7);
+    thread.join().unwrap();
+}
+
+#[test]
+fn bind_in_range_skips_ports_in_use() {
+    let ip: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let taken = UdpSocket::bind(ip).unwrap();
+    let port = taken.local_addr().unwrap().port();
+
+    let range = port..=port;
+    let error = bind_in_range(ip, Some(&range)).err().unwrap();
+    assert_eq!(error.kind(), io::ErrorKind::AddrInUse);
+    assert!(bind_in_range(ip, Some(&(port..=port - 1))).is_err());
+
+    drop(taken);
+    let socket = bind_in_range(ip, Some(&range)).unwrap();
+    assert_eq!(socket.local_addr().unwrap().port(), port);
+}
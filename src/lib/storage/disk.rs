@@ -0,0 +1,385 @@
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::storage::{Storage, Source, Upload};
+
+// Distinguishes the temporary files of uploads started by this process
+static UPLOAD_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// The start of the name of every upload's temporary file. Files named like
+// this can't be read or written by clients.
+const TEMP_PREFIX: &str = ".tftprs-upload.";
+
+/// Stores files in a directory on disk. Symlinks inside the directory are
+/// followed, but only if they lead to somewhere else inside of it.
+///
+/// Uploads are written to a hidden temporary file next to their final path,
+/// which is synced to disk and moved into place when the upload is
+/// committed, so a partial upload is never served. Temporary files can't be
+/// read or written by clients, including any left behind by a crash.
+pub struct DiskStorage {
+    root: PathBuf
+}
+
+impl DiskStorage {
+
+    /// Create a `DiskStorage` that serves files from under `root`.
+    pub fn new<P: AsRef<Path>>(root: P) -> DiskStorage {
+        DiskStorage{
+            root: root.as_ref().to_path_buf()
+        }
+    }
+
+    // Find the file on disk for a path relative to the root. Fails with
+    // `PermissionDenied` if it leads outside of the root through a symlink,
+    // or names an upload's temporary file.
+    fn full_path(&self, path: &Path) -> io::Result<PathBuf> {
+        if is_temp_file(path) {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                      "path names an upload in progress"));
+        }
+
+        let full_path = self.root.join(path);
+        if is_within_root(&self.root, &full_path) {
+            Ok(full_path)
+        } else {
+            Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                               "path leads outside of the root"))
+        }
+    }
+}
+
+impl Storage for DiskStorage {
+    fn open(&self, path: &Path) -> io::Result<Source> {
+        let file = File::open(self.full_path(path)?)?;
+        let size = file.metadata()?.len();
+        Ok(Source{
+            reader: Box::new(file),
+            size: Some(size)
+        })
+    }
+
+    fn create(&self, path: &Path, overwrite: bool) -> io::Result<Box<Upload>> {
+        let path = self.full_path(path)?;
+        if !overwrite && fs::symlink_metadata(&path).is_ok() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "file exists"));
+        }
+
+        let (file, temp) = create_temp_file(&path)?;
+        Ok(Box::new(DiskUpload{
+            file: file,
+            temp: temp,
+            path: path,
+            overwrite: overwrite,
+            committed: false
+        }))
+    }
+
+    fn available_space(&self) -> Option<u64> {
+        available_space(&self.root)
+    }
+}
+
+// A file being written to a `DiskStorage`, by way of a temporary file in the
+// same directory. The temporary file is removed if the upload is dropped
+// before it is committed.
+struct DiskUpload {
+    file: File,
+    temp: PathBuf,
+    path: PathBuf,
+    overwrite: bool,
+    committed: bool
+}
+
+impl Write for DiskUpload {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Upload for DiskUpload {
+    fn commit(mut self: Box<Self>) -> io::Result<()> {
+        self.file.sync_all()?;
+
+        // Renaming replaces any existing file, so unless that is allowed,
+        // link the file into place instead, which fails if one was written
+        // while this upload was in progress. Some filesystems, such as FAT,
+        // have no hard links, so the file is moved without replacing
+        // anything there instead.
+        if self.overwrite {
+            fs::rename(&self.temp, &self.path)?;
+            self.committed = true;
+        } else {
+            match fs::hard_link(&self.temp, &self.path) {
+                Ok(()) => {
+                    self.committed = true;
+                    let _ = fs::remove_file(&self.temp);
+                },
+                Err(ref e) if links_unsupported(e) => {
+                    rename_no_replace(&self.temp, &self.path)?;
+                    self.committed = true;
+                },
+                Err(e) => return Err(e)
+            }
+        }
+
+        // Make sure the new link itself survives a crash. Not every platform
+        // can sync a directory, so failing to is not an error.
+        if let Some(dir) = self.path.parent() {
+            if let Ok(dir) = File::open(dir) {
+                let _ = dir.sync_all();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for DiskUpload {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.temp);
+        }
+    }
+}
+
+// Create a new, uniquely named temporary file in the same directory as
+// `path`, so that it can be moved into place without copying.
+fn create_temp_file(path: &Path) -> io::Result<(File, PathBuf)> {
+    let name = match path.file_name() {
+        Some(n) => n.to_string_lossy().into_owned(),
+        None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a file name"))
+    };
+
+    loop {
+        let count = UPLOAD_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let temp = path.with_file_name(format!("{}{}-{}.{}", TEMP_PREFIX, process::id(),
+                                               count, name));
+        match OpenOptions::new().write(true).create_new(true).open(&temp) {
+            Ok(file) => return Ok((file, temp)),
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e)
+        }
+    }
+}
+
+// Whether `error`, from making a hard link, may mean that the filesystem
+// doesn't support them. Linux reports this as a permission error.
+fn links_unsupported(error: &io::Error) -> bool {
+    match error.kind() {
+        io::ErrorKind::PermissionDenied | io::ErrorKind::Unsupported => true,
+        _ => false
+    }
+}
+
+// Move `from` to `to`, failing with `AlreadyExists` if there is already a
+// file at `to`. The check and the move happen at once, unless the
+// filesystem can't do that.
+#[cfg(target_os = "linux")]
+fn rename_no_replace(from: &Path, to: &Path) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let from_c = CString::new(from.as_os_str().as_bytes())?;
+    let to_c = CString::new(to.as_os_str().as_bytes())?;
+    let result = unsafe {
+        libc::renameat2(libc::AT_FDCWD, from_c.as_ptr(), libc::AT_FDCWD, to_c.as_ptr(),
+                        libc::RENAME_NOREPLACE)
+    };
+    if result == 0 {
+        return Ok(());
+    }
+
+    let error = io::Error::last_os_error();
+    match error.raw_os_error() {
+        // The kernel or the filesystem doesn't support the flag
+        Some(libc::EINVAL) | Some(libc::ENOSYS) | Some(libc::EOPNOTSUPP) => {
+            check_and_rename(from, to)
+        },
+        _ => Err(error)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn rename_no_replace(from: &Path, to: &Path) -> io::Result<()> {
+    check_and_rename(from, to)
+}
+
+// Move `from` to `to` unless there is already a file at `to`. A file that
+// appears between the check and the move is replaced, so this is only used
+// when nothing better is available.
+fn check_and_rename(from: &Path, to: &Path) -> io::Result<()> {
+    if fs::symlink_metadata(to).is_ok() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, "file exists"));
+    }
+    fs::rename(from, to)
+}
+
+// Whether `path` names the temporary file of an upload
+fn is_temp_file(path: &Path) -> bool {
+    match path.file_name() {
+        Some(name) => name.to_string_lossy().starts_with(TEMP_PREFIX),
+        None => false
+    }
+}
+
+// Check that `path`, which is lexically under `root`, does not lead outside
+// of it through a symlink. The path may not exist yet (for a write), in which
+// case its closest existing ancestor is checked instead.
+fn is_within_root(root: &Path, path: &Path) -> bool {
+    let root = match fs::canonicalize(root) {
+        Ok(r) => r,
+        Err(_) => return false
+    };
+
+    let mut existing = path;
+    loop {
+        if fs::symlink_metadata(existing).is_ok() {
+            // A dangling symlink fails to resolve here, so is refused as
+            // well, as writing to it would create its target
+            return match fs::canonicalize(existing) {
+                Ok(resolved) => resolved.starts_with(&root),
+                Err(_) => false
+            };
+        }
+
+        existing = match existing.parent() {
+            Some(parent) => parent,
+            None => return false
+        };
+    }
+}
+
+// Returns the number of bytes available to unprivileged users on the
+// filesystem containing `path`, or None if it cannot be determined.
+#[cfg(unix)]
+fn available_space(path: &Path) -> Option<u64> {
+    use std::ffi::CString;
+    use std::mem;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = match CString::new(path.as_os_str().as_bytes()) {
+        Ok(p) => p,
+        Err(_) => return None
+    };
+
+    unsafe {
+        let mut stat: libc::statvfs = mem::zeroed();
+        if libc::statvfs(path.as_ptr(), &mut stat) != 0 {
+            return None;
+        }
+        Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+    }
+}
+
+#[cfg(not(unix))]
+fn available_space(_: &Path) -> Option<u64> {
+    None
+}
+
+// Create an empty directory for a test to store files in
+#[cfg(test)]
+fn test_root(name: &str) -> PathBuf {
+    use std::env;
+
+    let root = env::temp_dir().join(format!("tftprs-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+    root
+}
+
+#[test]
+fn disk_storage_hides_uploads_in_progress() {
+    let root = test_root("hidden-uploads");
+    let storage = DiskStorage::new(&root);
+
+    let mut upload = storage.create(Path::new("img.bin"), false).unwrap();
+    upload.write_all(&[0; 512]).unwrap();
+
+    // The partial upload is on disk, but can't be read or written through
+    // the storage, even once it has been abandoned
+    let temp = fs::read_dir(&root).unwrap().next().unwrap().unwrap().file_name();
+    let temp = Path::new(&temp);
+    assert_eq!(storage.open(temp).err().unwrap().kind(), io::ErrorKind::PermissionDenied);
+    assert_eq!(storage.create(temp, true).err().unwrap().kind(),
+               io::ErrorKind::PermissionDenied);
+
+    fs::write(root.join(".tftprs-upload.1-0.img.bin"), b"left by a crash").unwrap();
+    let leftover = Path::new(".tftprs-upload.1-0.img.bin");
+    assert_eq!(storage.open(leftover).err().unwrap().kind(), io::ErrorKind::PermissionDenied);
+
+    drop(upload);
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn disk_upload_commit() {
+    use std::io::Read;
+
+    let root = test_root("commit");
+    let storage = DiskStorage::new(&root);
+    let files = |root: &Path| fs::read_dir(root).unwrap().count();
+
+    let mut upload = storage.create(Path::new("new.bin"), false).unwrap();
+    upload.write_all(b"uploaded").unwrap();
+    assert!(storage.open(Path::new("new.bin")).is_err());
+    upload.commit().unwrap();
+    let mut contents = vec![];
+    storage.open(Path::new("new.bin")).unwrap().reader.read_to_end(&mut contents).unwrap();
+    assert_eq!(contents, b"uploaded".to_vec());
+    assert_eq!(files(&root), 1);
+
+    // Uploads that are never committed leave nothing behind
+    let mut upload = storage.create(Path::new("partial.bin"), false).unwrap();
+    upload.write_all(b"partial").unwrap();
+    drop(upload);
+    assert_eq!(files(&root), 1);
+
+    // A file written while a create-only upload is in progress is kept
+    let mut upload = storage.create(Path::new("raced.bin"), false).unwrap();
+    upload.write_all(b"uploaded").unwrap();
+    fs::write(root.join("raced.bin"), b"written first").unwrap();
+    assert_eq!(upload.commit().err().unwrap().kind(), io::ErrorKind::AlreadyExists);
+    assert_eq!(fs::read(root.join("raced.bin")).unwrap(), b"written first".to_vec());
+    assert_eq!(files(&root), 2);
+
+    // Unless the upload may replace it
+    let mut upload = storage.create(Path::new("raced.bin"), true).unwrap();
+    upload.write_all(b"replaced").unwrap();
+    upload.commit().unwrap();
+    assert_eq!(fs::read(root.join("raced.bin")).unwrap(), b"replaced".to_vec());
+    assert_eq!(files(&root), 2);
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn disk_rename_without_replacing() {
+    let root = test_root("rename");
+    let (from, to) = (root.join("from"), root.join("to"));
+
+    for rename in &[rename_no_replace, check_and_rename] {
+        fs::write(&from, b"uploaded").unwrap();
+        rename(&from, &to).unwrap();
+        assert_eq!(fs::read(&to).unwrap(), b"uploaded".to_vec());
+        assert!(fs::symlink_metadata(&from).is_err());
+
+        // Neither file is touched if there is already one in the way
+        fs::write(&from, b"raced").unwrap();
+        assert_eq!(rename(&from, &to).err().unwrap().kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read(&to).unwrap(), b"uploaded".to_vec());
+        assert_eq!(fs::read(&from).unwrap(), b"raced".to_vec());
+        fs::remove_file(&from).unwrap();
+        fs::remove_file(&to).unwrap();
+    }
+
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::{Cursor, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::storage::{Storage, Source, Upload};
+use crate::sync::lock;
+
+type Files = HashMap<PathBuf, Vec<u8>>;
+
+/// Stores files in memory. Clones share the same files, so a clone can be
+/// kept to add fixtures or inspect uploads while a server is using it.
+#[derive(Clone, Default)]
+pub struct MemoryStorage {
+    files: Arc<Mutex<Files>>
+}
+
+impl MemoryStorage {
+
+    /// Create an empty `MemoryStorage`.
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::default()
+    }
+
+    /// Add a file with the given contents, replacing any file already at
+    /// `path`.
+    pub fn insert<P: AsRef<Path>, D: Into<Vec<u8>>>(&self, path: P, data: D) {
+        lock(&self.files).insert(path.as_ref().to_path_buf(), data.into());
+    }
+
+    /// The contents of the file at `path`, if there is one.
+    pub fn get<P: AsRef<Path>>(&self, path: P) -> Option<Vec<u8>> {
+        lock(&self.files).get(path.as_ref()).cloned()
+    }
+
+    /// Remove the file at `path`, returning its contents.
+    pub fn remove<P: AsRef<Path>>(&self, path: P) -> Option<Vec<u8>> {
+        lock(&self.files).remove(path.as_ref())
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn open(&self, path: &Path) -> io::Result<Source> {
+        match self.get(path) {
+            Some(data) => Ok(Source{
+                size: Some(data.len() as u64),
+                reader: Box::new(Cursor::new(data))
+            }),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "no such file"))
+        }
+    }
+
+    fn create(&self, path: &Path, overwrite: bool) -> io::Result<Box<Upload>> {
+        if !overwrite && lock(&self.files).contains_key(path) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "file exists"));
+        }
+
+        Ok(Box::new(MemoryUpload{
+            files: self.files.clone(),
+            path: path.to_path_buf(),
+            overwrite: overwrite,
+            data: vec![]
+        }))
+    }
+}
+
+// A file being written to a `MemoryStorage`. The contents are only stored
+// once the upload is committed.
+struct MemoryUpload {
+    files: Arc<Mutex<Files>>,
+    path: PathBuf,
+    overwrite: bool,
+    data: Vec<u8>
+}
+
+impl Write for MemoryUpload {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.data.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Upload for MemoryUpload {
+    fn commit(self: Box<Self>) -> io::Result<()> {
+        let upload = *self;
+        let mut files = lock(&upload.files);
+        if !upload.overwrite && files.contains_key(&upload.path) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "file exists"));
+        }
+        files.insert(upload.path, upload.data);
+        Ok(())
+    }
+}
+
+#[test]
+fn memory_storage_round_trip() {
+    use std::io::Read;
+
+    let storage = MemoryStorage::new();
+    storage.insert("boot/pxelinux.0", &b"fixture"[..]);
+
+    let mut source = storage.open(Path::new("boot/pxelinux.0")).unwrap();
+    let mut contents = vec![];
+    source.reader.read_to_end(&mut contents).unwrap();
+    assert_eq!(contents, b"fixture".to_vec());
+    assert_eq!(source.size, Some(7));
+
+    let mut upload = storage.create(Path::new("upload.bin"), false).unwrap();
+    upload.write_all(b"uploaded").unwrap();
+    assert_eq!(storage.get("upload.bin"), None);
+    upload.commit().unwrap();
+    assert_eq!(storage.get("upload.bin"), Some(b"uploaded".to_vec()));
+
+    // Uploads that are never committed are discarded
+    let mut upload = storage.create(Path::new("partial.bin"), false).unwrap();
+    upload.write_all(b"partial").unwrap();
+    drop(upload);
+    assert_eq!(storage.get("partial.bin"), None);
+
+    let exists = storage.create(Path::new("boot/pxelinux.0"), false).err().unwrap();
+    assert_eq!(exists.kind(), io::ErrorKind::AlreadyExists);
+    storage.create(Path::new("boot/pxelinux.0"), true).unwrap().commit().unwrap();
+    assert_eq!(storage.get("boot/pxelinux.0"), Some(vec![]));
+    let missing = storage.open(Path::new("missing")).err().unwrap();
+    assert_eq!(missing.kind(), io::ErrorKind::NotFound);
+}
@@ -0,0 +1,51 @@
+use std::io;
+use std::io::{Read, Write};
+use std::path::Path;
+
+mod disk;
+mod memory;
+
+pub use self::disk::DiskStorage;
+pub use self::memory::MemoryStorage;
+
+/// A file that has been opened for reading
+pub struct Source {
+    /// The contents of the file
+    pub reader: Box<Read + Send>,
+
+    /// The size of the file in bytes, if it is known ahead of time
+    pub size: Option<u64>
+}
+
+/// A file being written to a `Storage`. Nothing appears at its path until
+/// the upload is committed, and an upload that is dropped without being
+/// committed is discarded.
+pub trait Upload: Write + Send {
+    /// Make the written file visible at its path. Fails with
+    /// `AlreadyExists` if a file has appeared there since the upload was
+    /// created and is not to be replaced.
+    fn commit(self: Box<Self>) -> io::Result<()>;
+}
+
+/// Somewhere for the server to read files from and write files to.
+///
+/// Paths given to a `Storage` are relative, and have already had any `.`
+/// and `..` components resolved, so they never name a parent of the place
+/// files are stored. Errors are reported to the client according to their
+/// kind: `NotFound`, `AlreadyExists` and `PermissionDenied` are sent as
+/// the matching TFTP errors.
+pub trait Storage: Send + Sync {
+    /// Open the file at `path` for reading.
+    fn open(&self, path: &Path) -> io::Result<Source>;
+
+    /// Start writing a file at `path`. If there is already a file at
+    /// `path`, it is replaced once the upload is committed if `overwrite` is
+    /// true, and otherwise this fails with `AlreadyExists`.
+    fn create(&self, path: &Path, overwrite: bool) -> io::Result<Box<Upload>>;
+
+    /// The number of bytes that can still be written, or None if it is
+    /// not known.
+    fn available_space(&self) -> Option<u64> {
+        None
+    }
+}
@@ -0,0 +1,13 @@
+use std::sync::{Mutex, MutexGuard};
+
+/// Lock a mutex, even if a thread panicked while holding it. A transfer that
+/// panics is caught and the server carries on, so a poisoned lock must not
+/// take every later request down with it. The state behind each of the
+/// server's locks is only changed in single steps, so is never left
+/// inconsistent.
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner()
+    }
+}
@@ -1,210 +1,1094 @@
-use std::net::{UdpSocket, SocketAddr};
-use std::fs::File;
+use std::net::SocketAddr;
+use std::io;
 use std::io::{Write, Read};
-use std::path::PathBuf;
-
-use config::Config;
-use packet::error::{TftpError, translate_io_error};
-use codes::{ErrorCode, TransferMode};
-use packet::Packet;
-use packet::data;
-use packet::data::TftpData;
-use packet::ack::TftpAck;
-
-// Receive a file at `path` from `addr`. If the file is successfully received,
-// Ok(file) is returned. Otherwise, a TftpError is returned.
-pub fn recieve_file(config: &Config, socket: &UdpSocket, path: &PathBuf,
-                    _: &TransferMode, addr: SocketAddr) -> Result<File, TftpError> {
-    if path.exists() {
-        return Err(TftpError{
-            code: ErrorCode::FileExists,
+use std::collections::VecDeque;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::config::{Config, WritePolicy};
+use crate::options::TransferOptions;
+use crate::packet::error::{TftpError, translate_io_error};
+use crate::codes::{ErrorCode, TransferMode};
+use crate::packet::Packet;
+use crate::packet::data::TftpData;
+use crate::packet::ack::TftpAck;
+use crate::packet::oack::TftpOack;
+use crate::netascii::{self, NetAsciiEncoder, NetAsciiDecoder};
+use crate::storage::{Source, Upload};
+use crate::socket::TransferSocket;
+use crate::ratelimit::Throttle;
+
+/// The ways in which a transfer can fail
+#[derive(Debug)]
+pub enum TransferError {
+    /// The peer stopped responding
+    TimedOut,
+
+    /// The transfer could not continue on this side. The error should be
+    /// reported to the peer.
+    Local(TftpError),
+
+    /// The peer aborted the transfer with an ERROR packet
+    Remote(TftpError)
+}
+
+impl TransferError {
+
+    /// The ERROR to send to the peer for this failure, or None if the peer
+    /// has already given up on the transfer.
+    pub fn as_tftp_error(&self) -> Option<TftpError> {
+        match *self {
+            TransferError::TimedOut => Some(TftpError{
+                code: ErrorCode::Undefined,
+                message: Some("Exceeded max send attempts".to_string())
+            }),
+            TransferError::Local(ref e) => Some(e.clone()),
+            TransferError::Remote(_) => None
+        }
+    }
+}
+
+// Receive a file at `path` from `addr` into the configured storage. The file
+// only appears at `path` once it has been received in full. If the file is
+// successfully received, Ok(()) is returned. Otherwise, a TransferError is
+// returned.
+pub async fn recieve_file<S: TransferSocket>(config: &Config, socket: &S, path: &Path,
+                                             mode: &TransferMode, options: &TransferOptions,
+                                             addr: SocketAddr) -> Result<(), TransferError> {
+    let overwrite = match *config.write_policy.for_path(path) {
+        WritePolicy::Overwrite => true,
+        WritePolicy::CreateOnly => false,
+        _ => return Err(TransferError::Local(TftpError{
+            code: ErrorCode::AccessViolation,
             message: None
-        });
+        }))
+    };
+
+    let mut acknowledged = options.acknowledged.clone();
+
+    // Refuse uploads that are known to be too large before anything is
+    // written (RFC 2349)
+    if let Some(size) = options.transfer_size {
+        let too_large = match config.max_upload_size {
+            Some(max) => size > max,
+            None => false
+        };
+        let storage = config.storage.clone();
+        let no_space = match socket.run_blocking(Box::new(move || storage.available_space())).await {
+            Some(available) => size > available,
+            None => false
+        };
+        if too_large || no_space {
+            return Err(TransferError::Local(TftpError{
+                code: ErrorCode::DiskFull,
+                message: None
+            }));
+        }
+        acknowledged.push(("tsize".to_string(), size.to_string()));
     }
 
-    let mut file = match File::create(path) {
-        Ok(f) => f,
-        Err(e) => return Err(translate_io_error(e.kind()))
+    // If the transfer fails, the upload is dropped here without being
+    // committed, which discards it
+    let storage = config.storage.clone();
+    let target = path.to_path_buf();
+    let mode = *mode;
+    let create = move || storage.create(&target, overwrite).map(|u| Incoming::new(u, mode));
+    let mut upload = match socket.run_blocking(Box::new(create)).await {
+        Ok(u) => Blocking::new(u),
+        Err(e) => return Err(TransferError::Local(translate_io_error(e.kind())))
     };
 
     if let Some(ref callback) = config.file_write_started_callback {
-        callback.call(&path, &file);
+        callback.call(path, &addr);
     }
 
-    // The buffer to receive data into. Max size is 512 payload bytes plus
-    // 2 for opcode and 2 for
-    let mut resp_buffer = [0u8; data::MAX_DATA_SIZE + 2 + 2];
-    for number in 0.. {
-        let mut attempts = 0;
-        while attempts <= config.send_retry_attempts {
-            attempts += 1;
+    // If any options were accepted, the transfer starts with an OACK
+    // instead of ACK 0 (RFC 2347)
+    let response = if !acknowledged.is_empty() {
+        TftpOack{options: acknowledged}.as_packet()
+    } else {
+        TftpAck{number: 0}.as_packet()
+    };
 
-            let ack = TftpAck{number: number};
-            socket.send_to(&ack.as_packet(), addr).unwrap();
+    receive_blocks(socket, addr, options, config.send_retry_attempts,
+                   &mut upload, 0, response, config.max_upload_size).await?;
 
-            let (count, resp_addr) = match socket.recv_from(&mut resp_buffer) {
-                Ok(r) => r,
+    let upload = upload.into_inner();
+    match socket.run_blocking(Box::new(move || upload.commit())).await {
+        Ok(()) => Ok(()),
+        Err(e) => Err(TransferError::Local(translate_io_error(e.kind())))
+    }
+}
 
-                // Different platforms are allowed to return different
-                // error codes for timeouts, so just assume any error
-                // is a timeout and try again
-                Err(_) => continue
-            };
+// Send the file at `path` to `target_addr`. The contents are read from
+// `source` if one is given, and from the configured storage otherwise. If
+// the transfer completes successfully, Ok(()) is returned. Otherwise, a
+// TransferError is returned.
+pub async fn send_file<S: TransferSocket>(config: &Config, socket: &S, path: &Path,
+                                          source: Option<Source>, mode: &TransferMode,
+                                          options: &TransferOptions, target_addr: SocketAddr)
+                                          -> Result<(), TransferError> {
+    let from_storage = source.is_none();
+    let mut source = match source {
+        Some(s) => s,
+        None => match open(config, socket, path).await {
+            Ok(s) => s,
+            Err(e) => return Err(TransferError::Local(translate_io_error(e.kind())))
+        }
+    };
 
-            // Receiving a packet from unexpected source does not
-            // interrupt the operation with the current client
-            if resp_addr != addr {
-                let _ = socket.send_to(&TftpError{
-                    code: ErrorCode::UnknownTransferID,
-                    message: None
-                }.as_packet(), resp_addr);
-                continue;
-            }
+    if let Some(ref callback) = config.file_read_started_callback {
+        callback.call(path, &target_addr);
+    }
 
-            let data =
-                match TftpData::from_buffer(&resp_buffer[..count]) {
-                    Some(d) => d,
-                    None => continue
+    let mut acknowledged = options.acknowledged.clone();
+    if options.transfer_size.is_some() {
+        // The size reported must be the size after translation, which can
+        // only be found by reading the whole file and opening it again, so
+        // isn't possible for a stream given by a read handler. If the size
+        // can't be found, the option is simply not acknowledged.
+        let size = match *mode {
+            TransferMode::NetAscii if !from_storage => None,
+            TransferMode::NetAscii => {
+                let mut reader = source.reader;
+                let counted = socket.run_blocking(Box::new(move || netascii::encoded_size(&mut reader))).await;
+                let reopened = match counted {
+                    Ok(size) => open(config, socket, path).await.map(|s| (size, s)),
+                    Err(e) => Err(e)
                 };
-
-            // This is an unexpected data packet (probably a retransmission)
-            // so ack again
-            if data.number != number+1 {
-                continue;
-            } else {
-
-                // This is the expected packet, so write it out
-                if let Err(_) = file.write_all(&data.data) {
-                    return Err(TftpError{
-                        code: ErrorCode::Undefined,
-                        message: None
-                    });
+                match reopened {
+                    Ok((size, reopened)) => {
+                        source = reopened;
+                        Some(size)
+                    },
+                    Err(e) => return Err(TransferError::Local(translate_io_error(e.kind())))
                 }
+            },
+            TransferMode::Octet => source.size
+        };
+        if let Some(size) = size {
+            acknowledged.push(("tsize".to_string(), size.to_string()));
+        }
+    }
+
+    let throttle = match config.rate_limiter {
+        Some(ref limiter) => Some(limiter.throttle(target_addr.ip())),
+        None => None
+    };
+
+    // If any options were accepted, the client must acknowledge the OACK
+    // with ACK 0 before the first data packet is sent (RFC 2347)
+    if !acknowledged.is_empty() {
+        let oack = TftpOack{options: acknowledged};
+        send_packet(socket, target_addr, &oack.as_packet(), 0,
+                    config.send_retry_attempts).await?;
+    }
+
+    let reader: Box<dyn Read + Send> = match *mode {
+        TransferMode::NetAscii => Box::new(NetAsciiEncoder::new(source.reader)),
+        TransferMode::Octet => source.reader
+    };
+    send_blocks(socket, target_addr, options, config.send_retry_attempts,
+                &mut Blocking::new(Outgoing(reader)), throttle.as_ref()).await?;
+    Ok(())
+}
+
+// Open the file at `path` in the configured storage
+async fn open<S: TransferSocket>(config: &Config, socket: &S, path: &Path) -> io::Result<Source> {
+    let storage = config.storage.clone();
+    let path = path.to_path_buf();
+    socket.run_blocking(Box::new(move || storage.open(&path))).await
+}
+
+// Receive DATA packets from `peer` and write them to `file` until the final
+// (short) block arrives. `response` is the packet acknowledging block
+// `last_number`, the last block received so far, and is sent first. If more
+// than `max_size` bytes arrive, the transfer is aborted. Returns the number
+// of bytes received.
+pub async fn receive_blocks<S: TransferSocket, W: WriteBlock<S>>(socket: &S, peer: SocketAddr,
+                                                                 options: &TransferOptions, retries: u8,
+                                                                 file: &mut W, last_number: u16,
+                                                                 response: Vec<u8>, max_size: Option<u64>)
+                                                                 -> Result<u64, TransferError> {
+    // The buffer to receive data into. Max size is the negotiated block size
+    // plus 2 for opcode and 2 for the block number
+    let mut resp_buffer = vec![0u8; options.block_size + 2 + 2];
+    let mut bytes_received = 0;
 
-                if data.data.len() < data::MAX_DATA_SIZE {
+    let mut response = response;
+    send(socket, &response, peer).await?;
 
-                    // No further packets, so stop
-                    let ack = TftpAck{number: number+1};
-                    socket.send_to(&ack.as_packet(), addr).unwrap();
-                    return Ok(file);
+    // The number of the last block received in order, and how many have
+    // arrived since the last ACK. The sender only waits for an ACK after
+    // each full window (RFC 7440).
+    let mut last_number = last_number;
+    let mut unacknowledged = 0;
+    let mut gap_acknowledged = false;
+    let mut attempts = 0;
+    loop {
+        let (count, resp_addr) = match socket.recv_from(&mut resp_buffer).await {
+            Ok(r) => r,
+
+            // Different platforms are allowed to return different
+            // error codes for timeouts, so just assume any error
+            // is a timeout and re-send the last ACK
+            Err(_) => {
+                attempts += 1;
+                if attempts > retries {
+                    return Err(TransferError::TimedOut);
                 }
-                break;
+                send(socket, &response, peer).await?;
+                continue;
             }
+        };
+
+        // Receiving a packet from unexpected source does not
+        // interrupt the operation with the current client
+        if resp_addr != peer {
+            let _ = socket.send_to(&TftpError{
+                code: ErrorCode::UnknownTransferID,
+                message: None
+            }.as_packet(), resp_addr).await;
+            continue;
         }
-        if attempts > config.send_retry_attempts {
-            return Err(TftpError{
-                code: ErrorCode::Undefined,
-                message: Some("Exceeded max send attempts".to_string())
-            });
+
+        if let Some(error) = TftpError::from_buffer(&resp_buffer[..count]) {
+            return Err(TransferError::Remote(error));
+        }
+
+        let data =
+            match TftpData::from_buffer(&resp_buffer[..count]) {
+                Some(d) => d,
+                None => continue
+            };
+
+        // This is an unexpected data packet (a retransmission, or part of a
+        // window after a lost block), so ack the last block received in
+        // order to make the sender restart from there. Only do this once per
+        // gap, as the rest of the window is likely to follow.
+        if data.number != options.next_block(last_number) {
+            if !gap_acknowledged {
+                response = TftpAck{number: last_number}.as_packet();
+                send(socket, &response, peer).await?;
+                gap_acknowledged = true;
+                unacknowledged = 0;
+            }
+            continue;
+        }
+
+        // Peers that did not declare a size up front are still
+        // held to the size limit
+        bytes_received += data.data.len() as u64;
+        if let Some(max) = max_size {
+            if bytes_received > max {
+                return Err(TransferError::Local(TftpError{
+                    code: ErrorCode::DiskFull,
+                    message: None
+                }));
+            }
+        }
+
+        // This is the expected packet, so write it out
+        let number = data.number;
+        let length = data.data.len();
+        if let Err(e) = file.write_block(socket, data.data).await {
+            return Err(TransferError::Local(translate_io_error(e.kind())));
+        }
+
+        last_number = number;
+        unacknowledged += 1;
+        gap_acknowledged = false;
+        attempts = 0;
+
+        if length < options.block_size {
+
+            // No further packets, so stop
+            let ack = TftpAck{number: last_number};
+            send(socket, &ack.as_packet(), peer).await?;
+            return Ok(bytes_received);
+        } else if unacknowledged == options.window_size {
+            response = TftpAck{number: last_number}.as_packet();
+            send(socket, &response, peer).await?;
+            unacknowledged = 0;
         }
     }
-    unreachable!();
 }
 
-// Send the file at `path` to `target_addr`. If the transfer completes
-// successfully, Ok(file) is returned. Otherwise, a TftpError is returned.
-pub fn send_file(config: &Config, socket: &UdpSocket, path: &PathBuf,
-                 _: &TransferMode, target_addr: SocketAddr) -> Result<File, TftpError> {
-    if !path.exists() {
-        return Err(TftpError{
-            code: ErrorCode::FileNotFound,
-            message: None
-        });
-    }
+// Send the contents of `file` to `peer` as DATA packets, starting from
+// block 1, until every block has been acknowledged. If a throttle is given,
+// each packet waits for it before being sent. Returns the number of bytes
+// sent.
+pub async fn send_blocks<S: TransferSocket, R: ReadBlock<S>>(socket: &S, peer: SocketAddr,
+                                                             options: &TransferOptions, retries: u8,
+                                                             file: &mut R, throttle: Option<&Throttle>)
+                                                             -> Result<u64, TransferError> {
+    let mut resp_buffer = [0u8; RESPONSE_BUFFER_SIZE];
+    let mut bytes_sent = 0;
 
-    let mut file = match File::open(path) {
-        Ok(f) => f,
-        Err(e) => return Err(translate_io_error(e.kind()))
-    };
+    // The blocks that have been sent but not yet acknowledged, oldest first
+    let mut window: VecDeque<TftpData> = VecDeque::with_capacity(options.window_size);
+    let mut next_number: u16 = 1;
+    let mut end_of_file = false;
+    let mut attempts = 0;
 
-    if let Some(ref callback) = config.file_read_started_callback {
-        callback.call(&path, &file);
-    }
+    // The last block acknowledged, which is 0 (for the request or the OACK)
+    // before any data has been sent
+    let mut last_acknowledged: u16 = 0;
 
-    // We just need a 4 byte buffer for the ACK
-    //FIXME: this allows larger messages that happen to start
-    //       with the right bytes to be accepted
-    let mut resp_buffer = [0u8; 4];
-    let mut previous_bytes_sent = 0;
+    loop {
+        // Top up the window with new blocks. The last block is always
+        // shorter than the block size (possibly empty), to show the end.
+        while window.len() < options.window_size && !end_of_file {
+            // If this is the end of the file, less than a block is read,
+            // so the packet won't be padded with zeros
+            let data = match file.read_block(socket, options.block_size).await {
+                Ok(d) => d,
+                Err(e) => return Err(TransferError::Local(translate_io_error(e.kind())))
+            };
+            end_of_file = data.len() < options.block_size;
 
-    let mut data_packet = TftpData{
-        number: 0,
-        data: vec![0u8; data::MAX_DATA_SIZE]
-    };
+            window.push_back(TftpData{
+                number: next_number,
+                data: data
+            });
+            next_number = options.next_block(next_number);
+        }
 
-    for number in 1.. {
-        data_packet.number = number;
-        data_packet.data.reserve(data::MAX_DATA_SIZE);
+        // Everything, including the final short block, has been acknowledged
+        if window.is_empty() {
+            return Ok(bytes_sent);
+        }
 
-        let file_bytes = match file.read(&mut data_packet.data) {
-            Ok(b) => b,
-            Err(e) => return Err(translate_io_error(e.kind()))
-        };
+        for packet in &window {
+            let packet = packet.as_packet();
+            if let Some(throttle) = throttle {
+                let delay = throttle.delay(packet.len());
+                if delay > Duration::from_secs(0) {
+                    socket.pause(delay).await;
+                }
+            }
+            send(socket, &packet, peer).await?;
+        }
+
+        // Wait for the peer to acknowledge part of the window. ACKs are
+        // cumulative, so every block up to the acknowledged one is done
+        // with. If none arrives in time, or the peer acknowledges the last
+        // block again to show that it missed the start of the window, the
+        // unacknowledged blocks are sent again.
+        loop {
+            let (count, resp_addr) = match socket.recv_from(&mut resp_buffer).await {
+                Ok(r) => r,
+                Err(_) => {
+                    attempts += 1;
+                    if attempts > retries {
+                        return Err(TransferError::TimedOut);
+                    }
+                    break;
+                }
+            };
 
-        // If this is the end of the file (we've read less than 512 bytes),
-        // then truncate the data vector so the packet won't be padded with
-        // zeros
-        //FIXME: read may return less than MAX_DATA_SIZE even when the file
-        // is not empty. Should probably read in a loop (or find a way to
-        // do this with read_exact).
-        if file_bytes < data::MAX_DATA_SIZE {
-            data_packet.data.truncate(file_bytes);
-        }
-
-        // A 0 byte file should still get a response, so make sure
-        // that we've sent one. Also, if the file length was a multiple
-        // of 512, we need to send a 0 size response to show the end.
-        // Otherwise, we're done
-        if file_bytes == 0 && number > 1 && previous_bytes_sent < data::MAX_DATA_SIZE {
-            return Ok(file);
-        } else {
-            previous_bytes_sent = file_bytes;
-            match send_data_packet(&config, &data_packet, socket,
-                                   &target_addr, &mut resp_buffer) {
-                Ok(()) => (),
-                Err(e) => return Err(e)
+            // Receiving a packet from unexpected source does not
+            // interrupt the operation with the current client
+            if resp_addr != peer {
+                let _ = socket.send_to(&TftpError{
+                    code: ErrorCode::UnknownTransferID,
+                    message: None
+                }.as_packet(), resp_addr).await;
+                continue;
+            }
+
+            if let Some(error) = TftpError::from_buffer(&resp_buffer[..count]) {
+                return Err(TransferError::Remote(error));
+            }
+
+            let ack = match TftpAck::from_buffer(&resp_buffer[..count]) {
+                Some(a) => a,
+                None => continue
+            };
+
+            // Other ACKs for blocks outside the window are stale duplicates
+            if let Some(position) = window.iter().position(|d| d.number == ack.number) {
+                for block in window.drain(..position + 1) {
+                    bytes_sent += block.data.len() as u64;
+                }
+                last_acknowledged = ack.number;
+                attempts = 0;
+                break;
+            } else if ack.number == last_acknowledged {
+                break;
             }
         }
     }
-    unreachable!();
 }
 
-// Send the TftpData packet `packet` to `target_addr` until an ACK is
-// received or 'send_retry_attempts' is exceeded.
-fn send_data_packet(config: &Config, packet: &TftpData, socket: &UdpSocket,
-                    target_addr: &SocketAddr, resp_buffer: &mut [u8]) -> Result<(), TftpError> {
+// Send the serialized `packet` to `target_addr` until an ACK for block
+// `number` is received or `retries` is exceeded.
+pub async fn send_packet<S: TransferSocket>(socket: &S, target_addr: SocketAddr, packet: &[u8],
+                                            number: u16, retries: u8) -> Result<(), TransferError> {
+    let mut resp_buffer = [0u8; RESPONSE_BUFFER_SIZE];
 
-    let expected_ack = TftpAck{number:packet.number};
+    let expected_ack = TftpAck{number: number};
     // Loop until we receive an ACK from the appropriate source
     let mut attempts = 0;
-    while attempts <= config.send_retry_attempts {
+    while attempts <= retries {
         attempts += 1;
 
-        socket.send_to(&packet.as_packet(), target_addr).unwrap();
-        let (count, resp_addr) = socket.recv_from(resp_buffer).unwrap();
-
-        let actual_ack = match TftpAck::from_buffer(&resp_buffer[..count]) {
-            Some(a) => a,
-            None => continue
+        send(socket, packet, target_addr).await?;
+        let (count, resp_addr) = match socket.recv_from(&mut resp_buffer).await {
+            Ok(r) => r,
+            Err(_) => continue
         };
 
         // Receiving a packet from unexpected source does not
         // interrupt the operation with the current client
-        if &resp_addr != target_addr {
+        if resp_addr != target_addr {
             let _ = socket.send_to(&TftpError{
                 code: ErrorCode::UnknownTransferID,
                 message: None
-            }.as_packet(), resp_addr);
-        } else if expected_ack == actual_ack {
-            // The fragment has been sent and acknowledged
-            break;
+            }.as_packet(), resp_addr).await;
+            continue;
+        }
+
+        if let Some(error) = TftpError::from_buffer(&resp_buffer[..count]) {
+            return Err(TransferError::Remote(error));
+        }
+
+        match TftpAck::from_buffer(&resp_buffer[..count]) {
+            // The packet has been sent and acknowledged
+            Some(ref ack) if *ack == expected_ack => return Ok(()),
+            _ => continue
         }
     }
-    if attempts > config.send_retry_attempts {
-        return Err(TftpError{
-            code: ErrorCode::Undefined,
-            message: Some("Exceeded max send attempts".to_string())
+    Err(TransferError::TimedOut)
+}
+
+// Large enough for an ACK, or an ERROR with a reasonable message
+const RESPONSE_BUFFER_SIZE: usize = 516;
+
+// Send `packet` to `addr`, failing the transfer if it cannot be sent
+async fn send<S: TransferSocket>(socket: &S, packet: &[u8],
+                                 addr: SocketAddr) -> Result<(), TransferError> {
+    match socket.send_to(packet, addr).await {
+        Ok(_) => Ok(()),
+        Err(e) => Err(TransferError::Local(translate_io_error(e.kind())))
+    }
+}
+
+/// Somewhere `send_blocks` reads a file from. Any reader is read directly,
+/// while reading a `Blocking` file is left to `TransferSocket::run_blocking`.
+pub trait ReadBlock<S: TransferSocket> {
+    /// Read the next `size` bytes of the file, or fewer at the end of it
+    async fn read_block(&mut self, socket: &S, size: usize) -> io::Result<Vec<u8>>;
+}
+
+/// Somewhere `receive_blocks` writes a file to. Any writer is written
+/// directly, while writing a `Blocking` file is left to
+/// `TransferSocket::run_blocking`.
+pub trait WriteBlock<S: TransferSocket> {
+    /// Write all of `data` to the file
+    async fn write_block(&mut self, socket: &S, data: Vec<u8>) -> io::Result<()>;
+}
+
+impl<S: TransferSocket, R: Read> ReadBlock<S> for R {
+    async fn read_block(&mut self, _: &S, size: usize) -> io::Result<Vec<u8>> {
+        read_block(self, size)
+    }
+}
+
+impl<S: TransferSocket, W: Write> WriteBlock<S> for W {
+    async fn write_block(&mut self, _: &S, data: Vec<u8>) -> io::Result<()> {
+        self.write_all(&data)
+    }
+}
+
+/// A file whose reads and writes may block for a while, such as one in
+/// storage, so that on an async socket they are made on a blocking thread
+/// rather than holding up other transfers.
+///
+/// The file is moved to the blocking thread and back, within a future that
+/// must be `Send` to be spawned. The compiler can't prove that for a future
+/// holding a boxed trait object this way, so files are wrapped in types of
+/// their own, such as `Outgoing` and `Incoming`.
+pub struct Blocking<F> {
+    // Only None while the file is in use on a blocking thread
+    file: Option<F>
+}
+
+impl<F> Blocking<F> {
+    pub fn new(file: F) -> Blocking<F> {
+        Blocking{
+            file: Some(file)
+        }
+    }
+
+    pub fn into_inner(self) -> F {
+        self.file.expect("file lost by a panicked read or write")
+    }
+
+    // Hand the file to `f` on a blocking thread, and take it back afterwards
+    async fn run<S, T, G>(&mut self, socket: &S, f: G) -> T
+        where S: TransferSocket, F: Send + 'static, T: Send + 'static,
+              G: FnOnce(F) -> (F, T) + Send + 'static {
+        let file = self.file.take().expect("file lost by a panicked read or write");
+        let (file, output) = socket.run_blocking(Box::new(move || f(file))).await;
+        self.file = Some(file);
+        output
+    }
+}
+
+impl<S: TransferSocket, R: Read + Send + 'static> ReadBlock<S> for Blocking<R> {
+    async fn read_block(&mut self, socket: &S, size: usize) -> io::Result<Vec<u8>> {
+        self.run(socket, move |mut file| {
+            let data = read_block(&mut file, size);
+            (file, data)
+        }).await
+    }
+}
+
+impl<S: TransferSocket, W: Write + Send + 'static> WriteBlock<S> for Blocking<W> {
+    async fn write_block(&mut self, socket: &S, data: Vec<u8>) -> io::Result<()> {
+        self.run(socket, move |mut file| {
+            let written = file.write_all(&data);
+            (file, written)
+        }).await
+    }
+}
+
+/// A file being sent from storage, translated to netascii if need be
+pub struct Outgoing(pub Box<dyn Read + Send>);
+
+impl Read for Outgoing {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+/// A file being received into storage, translated from netascii if need be
+pub enum Incoming {
+    Octet(Box<dyn Upload>),
+    NetAscii(NetAsciiDecoder<Box<dyn Upload>>)
+}
+
+impl Incoming {
+    pub fn new(upload: Box<dyn Upload>, mode: TransferMode) -> Incoming {
+        match mode {
+            TransferMode::NetAscii => Incoming::NetAscii(NetAsciiDecoder::new(upload)),
+            TransferMode::Octet => Incoming::Octet(upload)
+        }
+    }
+
+    /// Finish translating the file, and commit it to storage
+    pub fn commit(self) -> io::Result<()> {
+        match self {
+            Incoming::Octet(upload) => upload.commit(),
+            Incoming::NetAscii(decoder) => decoder.finish()?.commit()
+        }
+    }
+}
+
+impl Write for Incoming {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Incoming::Octet(ref mut upload) => upload.write(buf),
+            Incoming::NetAscii(ref mut decoder) => decoder.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Incoming::Octet(ref mut upload) => upload.flush(),
+            Incoming::NetAscii(ref mut decoder) => decoder.flush()
+        }
+    }
+}
+
+// Read from `file` until `size` bytes have been read or the end of the file
+// is reached. Less than `size` bytes are only returned at the end of the
+// file.
+fn read_block<R: Read>(file: &mut R, size: usize) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0u8; size];
+    let mut total = 0;
+    while total < size {
+        match file.read(&mut buf[total..]) {
+            Ok(0) => break,
+            Ok(n) => total += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e)
+        }
+    }
+    buf.truncate(total);
+    Ok(buf)
+}
+
+// Which way a packet passed through a `Proxy`
+#[cfg(test)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Direction {
+    ToServer,
+    ToClient
+}
+
+// Relays packets between a client and a server on loopback, so that tests
+// can see every packet of a transfer, and lose some of them
+#[cfg(test)]
+pub(crate) struct Proxy {
+    pub addr: SocketAddr,
+    packets: std::sync::Arc<std::sync::Mutex<Vec<(Direction, Vec<u8>)>>>,
+    stop: std::sync::Arc<std::sync::atomic::AtomicBool>
+}
+
+#[cfg(test)]
+impl Proxy {
+    // Relay packets sent to the proxy's address to and from `server`, except
+    // those for which `lose` returns true. Requests are sent to `server`, and
+    // other packets to whichever port the server last sent from, so each
+    // transfer follows its transfer ID.
+    pub fn start<F>(server: SocketAddr, lose: F) -> Proxy
+        where F: FnMut(Direction, &[u8]) -> bool + Send + 'static {
+        use std::net::UdpSocket;
+        use std::sync::{Arc, Mutex};
+        use std::sync::atomic::AtomicBool;
+
+        let front = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let back = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let proxy = Proxy{
+            addr: front.local_addr().unwrap(),
+            packets: Arc::new(Mutex::new(vec![])),
+            stop: Arc::new(AtomicBool::new(false))
+        };
+
+        // Where the client and server last sent from, and the choice of
+        // packets to lose, shared by both directions
+        let peers = Arc::new(Mutex::new((None, server)));
+        let lose = Arc::new(Mutex::new(lose));
+        let (front_clone, back_clone) = (front.try_clone().unwrap(), back.try_clone().unwrap());
+        proxy.relay(Direction::ToServer, front, back_clone, server, peers.clone(), lose.clone());
+        proxy.relay(Direction::ToClient, back, front_clone, server, peers, lose);
+        proxy
+    }
+
+    // Relay packets received on `from` in `direction` until the proxy is
+    // dropped, sending them from `to`
+    fn relay<F>(&self, direction: Direction, from: std::net::UdpSocket, to: std::net::UdpSocket,
+                server: SocketAddr,
+                peers: std::sync::Arc<std::sync::Mutex<(Option<SocketAddr>, SocketAddr)>>,
+                lose: std::sync::Arc<std::sync::Mutex<F>>)
+        where F: FnMut(Direction, &[u8]) -> bool + Send + 'static {
+        use std::sync::atomic::Ordering;
+        use std::thread;
+
+        from.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+        let (packets, stop) = (self.packets.clone(), self.stop.clone());
+        thread::spawn(move || {
+            let mut buffer = vec![0u8; 65536];
+            while !stop.load(Ordering::SeqCst) {
+                let (count, addr) = match from.recv_from(&mut buffer) {
+                    Ok(r) => r,
+                    Err(_) => continue
+                };
+                let target = {
+                    let mut peers = peers.lock().unwrap();
+                    match direction {
+                        Direction::ToServer => {
+                            peers.0 = Some(addr);
+                            let opcode = if count >= 2 { buffer[1] } else { 0 };
+                            if opcode == 1 || opcode == 2 { server } else { peers.1 }
+                        },
+                        Direction::ToClient => {
+                            peers.1 = addr;
+                            match peers.0 {
+                                Some(client) => client,
+                                None => continue
+                            }
+                        }
+                    }
+                };
+
+                // Hold the lock while sending, so that packets are recorded
+                // in the order they are relayed
+                let mut lose = lose.lock().unwrap();
+                if !(*lose)(direction, &buffer[..count]) {
+                    packets.lock().unwrap().push((direction, buffer[..count].to_vec()));
+                    to.send_to(&buffer[..count], target).unwrap();
+                }
+            }
         });
     }
-    Ok(())
+
+    // The numbers of the ACKs relayed in `direction`, in order
+    pub fn acks(&self, direction: Direction) -> Vec<u16> {
+        self.relayed(direction).iter()
+            .filter_map(|p| TftpAck::from_buffer(p).map(|a| a.number))
+            .collect()
+    }
+
+    // The DATA packets relayed in `direction`, in order
+    pub fn data(&self, direction: Direction) -> Vec<TftpData> {
+        self.relayed(direction).iter().filter_map(|p| TftpData::from_buffer(p)).collect()
+    }
+
+    fn relayed(&self, direction: Direction) -> Vec<Vec<u8>> {
+        self.packets.lock().unwrap().iter()
+            .filter(|&&(d, _)| d == direction)
+            .map(|&(_, ref p)| p.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+impl Drop for Proxy {
+    fn drop(&mut self) {
+        self.stop.store(true, std::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+// The contents of a file of `length` bytes for tests
+#[cfg(test)]
+pub(crate) fn test_file(length: usize) -> Vec<u8> {
+    (0..length).map(|i| (i % 251) as u8).collect()
+}
+
+// Whether `packet` is the first DATA packet for block `number`, so that a
+// `Proxy` loses it only once
+#[cfg(test)]
+fn first_data(seen: &mut bool, packet: &[u8], number: u16) -> bool {
+    match TftpData::from_buffer(packet) {
+        Some(ref d) if d.number == number && !*seen => {
+            *seen = true;
+            true
+        },
+        _ => false
+    }
+}
+
+#[test]
+fn windowed_transfer_acks_each_window() {
+    use crate::client::TftpClient;
+    use crate::server::{test_server, run_test_server};
+    use crate::storage::MemoryStorage;
+
+    let storage = MemoryStorage::new();
+    storage.insert("boot.img", test_file(512 * 10 + 100));
+    let (server, addr) = test_server(storage);
+    let handle = run_test_server(server);
+    let proxy = Proxy::start(addr, |_, _| false);
+
+    let mut client = TftpClient::new(proxy.addr).unwrap();
+    client.set_window_size(Some(4));
+    let mut received = vec![];
+    client.get_into("boot.img", &mut received).unwrap();
+    assert_eq!(received, test_file(512 * 10 + 100));
+
+    // The server's transfer is over once it has the last ACK
+    assert!(handle.shutdown(Some(Duration::from_secs(5))));
+
+    // Each block is sent once, and acknowledged along with the rest of its
+    // window, or as the last block
+    let sent: Vec<u16> = proxy.data(Direction::ToClient).iter().map(|d| d.number).collect();
+    assert_eq!(sent, (1..12).collect::<Vec<u16>>());
+    assert_eq!(proxy.acks(Direction::ToServer), vec![0, 4, 8, 11]);
+}
+
+#[test]
+fn windowed_transfer_ends_with_empty_block() {
+    use crate::client::TftpClient;
+    use crate::server::{test_server, run_test_server};
+    use crate::storage::MemoryStorage;
+
+    let storage = MemoryStorage::new();
+    let (server, addr) = test_server(storage.clone());
+    let handle = run_test_server(server);
+    let proxy = Proxy::start(addr, |_, _| false);
+
+    // A file that fills its last block needs an empty one after it, to show
+    // that there is nothing more
+    let mut client = TftpClient::new(proxy.addr).unwrap();
+    client.set_window_size(Some(4));
+    let data = test_file(512 * 8);
+    assert_eq!(client.put_from(&mut &data[..], Some(512 * 8), "upload.img").unwrap(), 512 * 8);
+
+    let sent = proxy.data(Direction::ToServer);
+    assert_eq!(sent.iter().map(|d| d.number).collect::<Vec<u16>>(), (1..10).collect::<Vec<u16>>());
+    assert!(sent[8].data.is_empty());
+    assert_eq!(proxy.acks(Direction::ToClient), vec![4, 8, 9]);
+
+    // The upload is committed once its last block has been acknowledged
+    assert!(handle.shutdown(Some(Duration::from_secs(5))));
+    assert_eq!(storage.get("upload.img"), Some(data));
+}
+
+#[test]
+fn windowed_transfer_recovers_lost_block() {
+    use std::time::Instant;
+    use crate::client::TftpClient;
+    use crate::server::{test_server, run_test_server};
+    use crate::storage::MemoryStorage;
+
+    // Download a file, losing the first DATA packet for block `number`.
+    // Returns the blocks sent, the blocks acknowledged, and how long it took.
+    let download = |number| {
+        let storage = MemoryStorage::new();
+        storage.insert("boot.img", test_file(512 * 10 + 100));
+        let (mut server, addr) = test_server(storage);
+
+        // The lost block must be noticed long before the server would give
+        // up waiting for an ACK
+        server.set_read_timeout(Some(Duration::from_secs(5)));
+        let handle = run_test_server(server);
+        let mut lost = false;
+        let proxy = Proxy::start(addr, move |direction, packet| {
+            direction == Direction::ToClient && first_data(&mut lost, packet, number)
+        });
+
+        let mut client = TftpClient::new(proxy.addr).unwrap();
+        client.set_window_size(Some(4));
+        let mut received = vec![];
+        let started = Instant::now();
+        client.get_into("boot.img", &mut received).unwrap();
+        let elapsed = started.elapsed();
+        assert_eq!(received, test_file(512 * 10 + 100));
+
+        // The server's transfer is over once it has the last ACK
+        assert!(handle.shutdown(Some(Duration::from_secs(5))));
+        let sent: Vec<u16> = proxy.data(Direction::ToClient).iter().map(|d| d.number).collect();
+        (sent, proxy.acks(Direction::ToServer), elapsed)
+    };
+
+    // Block 3 shows that block 2 was lost, so block 1 is acknowledged once
+    // (and not again for block 4), and the server carries on from block 2
+    let (sent, acks, elapsed) = download(2);
+    assert_eq!(sent, vec![1, 3, 4, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11]);
+    assert_eq!(acks, vec![0, 1, 5, 9, 11]);
+    assert!(elapsed < Duration::from_secs(1));
+
+    // Losing the first block of a window means acknowledging the end of the
+    // last window again, so the server sends the window again straight away
+    let (sent, acks, elapsed) = download(5);
+    assert_eq!(sent, vec![1, 2, 3, 4, 6, 7, 8, 5, 6, 7, 8, 9, 10, 11]);
+    assert_eq!(acks, vec![0, 4, 4, 8, 11]);
+    assert!(elapsed < Duration::from_secs(1));
+}
+
+#[test]
+fn windowed_transfer_restarts_after_timeout() {
+    use crate::client::TftpClient;
+    use crate::server::{test_server, run_test_server};
+    use crate::storage::MemoryStorage;
+
+    let storage = MemoryStorage::new();
+    storage.insert("boot.img", test_file(512 * 10 + 100));
+    let (server, addr) = test_server(storage);
+    let handle = run_test_server(server);
+    let mut lost = false;
+    let proxy = Proxy::start(addr, move |direction, packet| {
+        let is_ack = TftpAck::from_buffer(packet) == Some(TftpAck{number: 4});
+        if direction == Direction::ToServer && is_ack && !lost {
+            lost = true;
+            return true;
+        }
+        false
+    });
+
+    let mut client = TftpClient::new(proxy.addr).unwrap();
+    client.set_window_size(Some(4));
+    let mut received = vec![];
+    client.get_into("boot.img", &mut received).unwrap();
+    assert_eq!(received, test_file(512 * 10 + 100));
+
+    // The server's transfer is over once it has the last ACK
+    assert!(handle.shutdown(Some(Duration::from_secs(5))));
+
+    // Without an ACK, the server sends the whole window again once it times
+    // out. The client has all of it, so acknowledges it again at the first
+    // repeated block.
+    let sent: Vec<u16> = proxy.data(Direction::ToClient).iter().map(|d| d.number).collect();
+    assert_eq!(sent, vec![1, 2, 3, 4, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11]);
+    assert_eq!(proxy.acks(Direction::ToServer), vec![0, 4, 8, 11]);
+}
+
+#[test]
+fn transfer_rolls_over_block_numbers() {
+    use crate::client::TftpClient;
+    use crate::server::{test_server, run_test_server};
+    use crate::storage::MemoryStorage;
+
+    // More than 65535 blocks, so block numbers wrap around
+    let data = test_file(8 * 70000 + 3);
+    let storage = MemoryStorage::new();
+    storage.insert("big.img", data.clone());
+    let (server, addr) = test_server(storage.clone());
+    let handle = run_test_server(server);
+
+    let mut client = TftpClient::new(addr).unwrap();
+    client.set_block_size(Some(8));
+    client.set_window_size(Some(64));
+    let mut received = vec![];
+    client.get_into("big.img", &mut received).unwrap();
+    assert!(received == data);
+
+    // Wrapping around to block 1 instead, as negotiated by the client
+    client.set_block_rollover(Some(1));
+    let mut received = vec![];
+    client.get_into("big.img", &mut received).unwrap();
+    assert!(received == data);
+    client.put_from(&mut &data[..], None, "upload.img").unwrap();
+
+    assert!(handle.shutdown(Some(Duration::from_secs(5))));
+    assert!(storage.get("upload.img") == Some(data));
+}
+
+#[test]
+fn server_rolls_over_to_configured_block() {
+    use std::net::UdpSocket;
+    use crate::codes::Opcode;
+    use crate::packet::request::TftpRequest;
+    use crate::server::{test_server, run_test_server};
+    use crate::socket::block_on;
+    use crate::storage::MemoryStorage;
+
+    let data = test_file(8 * 70000 + 3);
+    let storage = MemoryStorage::new();
+    storage.insert("big.img", data.clone());
+    let (mut server, addr) = test_server(storage);
+    server.set_block_rollover(1);
+    let handle = run_test_server(server);
+
+    // A client that doesn't ask for the option has to expect the server's
+    // choice, as there is no way to be told
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    let requested = vec![("blksize".to_string(), "8".to_string()),
+                         ("windowsize".to_string(), "64".to_string())];
+    socket.send_to(&TftpRequest{
+        opcode: Opcode::ReadRequest,
+        filename: "big.img".to_string(),
+        mode: TransferMode::Octet,
+        options: requested.clone()
+    }.as_packet(), addr).unwrap();
+
+    let mut buffer = [0u8; 512];
+    let (count, peer) = socket.recv_from(&mut buffer).unwrap();
+    let oack = TftpOack::from_buffer(&buffer[..count]).unwrap();
+    let mut options = TransferOptions::from_oack(&requested, &oack.options).unwrap();
+    options.rollover = 1;
+
+    let mut received = vec![];
+    block_on(receive_blocks(&socket, peer, &options, 5, &mut received, 0,
+                            TftpAck{number: 0}.as_packet(), None)).unwrap();
+    assert!(received == data);
+    assert!(handle.shutdown(Some(Duration::from_secs(5))));
+}
+
+// Keeps files in memory, like `MemoryStorage`, but reports a fixed amount of
+// free space
+#[cfg(test)]
+struct LimitedStorage {
+    files: crate::storage::MemoryStorage,
+    space: u64
+}
+
+#[cfg(test)]
+impl crate::storage::Storage for LimitedStorage {
+    fn open(&self, path: &Path) -> io::Result<Source> {
+        self.files.open(path)
+    }
+
+    fn create(&self, path: &Path, overwrite: bool) -> io::Result<Box<dyn Upload>> {
+        self.files.create(path, overwrite)
+    }
+
+    fn available_space(&self) -> Option<u64> {
+        Some(self.space)
+    }
+}
+
+// Check that a transfer was refused or aborted because the file is too large
+#[cfg(test)]
+fn assert_disk_full<T: std::fmt::Debug>(result: Result<T, crate::error::Error>) {
+    match result {
+        Err(crate::error::Error::Remote(ref e)) if e.code == ErrorCode::DiskFull => (),
+        r => panic!("expected a disk full error: {:?}", r)
+    }
+}
+
+#[test]
+fn upload_too_large_is_refused_up_front() {
+    use crate::client::TftpClient;
+    use crate::server::{test_server, run_test_server};
+    use crate::storage::MemoryStorage;
+
+    let files = MemoryStorage::new();
+    let (mut server, addr) = test_server(LimitedStorage{files: files.clone(), space: 3000});
+    server.set_max_upload_size(Some(2000));
+    let handle = run_test_server(server);
+    let proxy = Proxy::start(addr, |_, _| false);
+    let client = TftpClient::new(proxy.addr).unwrap();
+    let data = test_file(4000);
+
+    // Over the server's limit, and over the space left in storage
+    assert_disk_full(client.put_from(&mut &data[..2500], Some(2500), "limit.img"));
+    assert_disk_full(client.put_from(&mut &data[..], Some(4000), "space.img"));
+    assert!(proxy.data(Direction::ToServer).is_empty());
+
+    assert_eq!(client.put_from(&mut &data[..1500], Some(1500), "fits.img").unwrap(), 1500);
+    assert!(handle.shutdown(Some(Duration::from_secs(5))));
+    assert_eq!(files.get("limit.img"), None);
+    assert_eq!(files.get("space.img"), None);
+    assert_eq!(files.get("fits.img"), Some(data[..1500].to_vec()));
+}
+
+#[test]
+fn upload_too_large_is_aborted() {
+    use crate::client::TftpClient;
+    use crate::server::{test_server, run_test_server};
+    use crate::storage::MemoryStorage;
+
+    let storage = MemoryStorage::new();
+    let (mut server, addr) = test_server(storage.clone());
+    server.set_max_upload_size(Some(2000));
+    let handle = run_test_server(server);
+    let proxy = Proxy::start(addr, |_, _| false);
+
+    // Without a size up front, the upload is stopped at the block that
+    // takes it over the limit, and nothing is kept
+    let client = TftpClient::new(proxy.addr).unwrap();
+    let data = test_file(4000);
+    assert_disk_full(client.put_from(&mut &data[..], None, "big.img"));
+    assert!(handle.shutdown(Some(Duration::from_secs(5))));
+    assert_eq!(proxy.data(Direction::ToServer).len(), 4);
+    assert_eq!(storage.get("big.img"), None);
+}
+
+#[test]
+fn read_request_reports_size() {
+    use std::sync::{Arc, Mutex};
+    use crate::client::TftpClient;
+    use crate::server::{test_server, run_test_server};
+    use crate::storage::MemoryStorage;
+
+    let storage = MemoryStorage::new();
+    storage.insert("boot.img", test_file(1234));
+    storage.insert("boot.cfg", "default linux\nprompt 0\n");
+    let (server, addr) = test_server(storage);
+    let handle = run_test_server(server);
+
+    // The size the server gives with `tsize` is passed on to the progress
+    // callback
+    let size = Arc::new(Mutex::new(None));
+    let mut client = TftpClient::new(addr).unwrap();
+    let reported = size.clone();
+    client.on_progress(move |_: &u64, total: &Option<u64>| {
+        *reported.lock().unwrap() = *total;
+    });
+
+    client.get_into("boot.img", &mut vec![]).unwrap();
+    assert_eq!(*size.lock().unwrap(), Some(1234));
+
+    // In netascii mode, it is the size after translation
+    client.set_mode(TransferMode::NetAscii);
+    let mut received = vec![];
+    client.get_into("boot.cfg", &mut received).unwrap();
+    assert_eq!(*size.lock().unwrap(), Some(25));
+    assert_eq!(received, b"default linux\nprompt 0\n".to_vec());
+    assert!(handle.shutdown(Some(Duration::from_secs(5))));
 }